@@ -1,22 +1,248 @@
-use anyhow::Result;
+use crate::problem::config;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 
-pub struct ArchiveStore {}
+pub struct ArchiveStore {
+    root: PathBuf,
+}
+
+// Distinguishes temporary files of concurrent writes to the same path
+static NEXT_TEMP_ID: AtomicU64 = AtomicU64::new(0);
 
-pub struct BlobHandle {}
+#[derive(Deserialize, Serialize)]
+pub struct BlobHandle {
+    hash: String,
+    size: u64,
+}
 
+#[derive(Deserialize, Serialize)]
 pub struct Archive {
     files: HashMap<String, ArchiveFile>,
 }
 
+#[derive(Deserialize, Serialize)]
 pub struct ArchiveFile {
     handle: BlobHandle,
     executable: bool,
 }
 
 impl ArchiveStore {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    fn get_blob_path(&self, hash: &str) -> Result<PathBuf> {
+        // The hash comes from the network, so make sure it cannot be used to escape the store
+        if hash.is_empty() || !hash.bytes().all(|c| matches!(c, b'0'..=b'9' | b'a'..=b'f')) {
+            bail!("Invalid blob hash {hash:?}");
+        }
+        Ok(self.root.join("blobs").join(hash))
+    }
+
+    pub async fn load_blob(&self, hash: &str) -> Result<Vec<u8>> {
+        let path = self.get_blob_path(hash)?;
+        tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Blob {hash} is not present in the archive store"))
+    }
+
     pub fn store_blob(&self, data: Vec<u8>) -> Result<BlobHandle> {
-        unimplemented!()
+        let hash = format!("{:x}", Sha256::digest(&data));
+        let handle = BlobHandle {
+            size: data.len() as u64,
+            hash,
+        };
+
+        let path = self.get_blob_path(&handle.hash)?;
+        if path
+            .try_exists()
+            .with_context(|| format!("Failed to check whether {path:?} exists"))?
+        {
+            // Blobs are immutable, so an existing blob with the same hash is the same blob
+            return Ok(handle);
+        }
+
+        write_atomically(&path, &data)?;
+
+        Ok(handle)
+    }
+
+    fn get_problem_dir(&self, problem_id: &str) -> Result<PathBuf> {
+        check_identifier(problem_id)?;
+        Ok(self.root.join("problems").join(problem_id))
+    }
+
+    fn get_revision_dir(&self, problem_id: &str, revision_id: &str) -> Result<PathBuf> {
+        check_identifier(revision_id)?;
+        Ok(self.get_problem_dir(problem_id)?.join(revision_id))
+    }
+
+    // Returns the numeric ids of all complete revisions of the problem in increasing order
+    pub fn list_revisions(&self, problem_id: &str) -> Result<Vec<u64>> {
+        let dir = self.get_problem_dir(problem_id)?;
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => Err(e).with_context(|| format!("Failed to list {dir:?}"))?,
+        };
+        let mut revisions = Vec::new();
+        for entry in entries {
+            let entry = entry.with_context(|| format!("Failed to list {dir:?}"))?;
+            let Some(revision_id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+            else {
+                continue;
+            };
+            if entry.path().join("revision.msgpack").exists() {
+                revisions.push(revision_id);
+            }
+        }
+        revisions.sort_unstable();
+        Ok(revisions)
+    }
+
+    // Reserves a fresh revision id for the problem. Ids are never reused, even if the import that
+    // reserved the id fails.
+    pub fn allocate_revision_id(&self, problem_id: &str) -> Result<String> {
+        let dir = self.get_problem_dir(problem_id)?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create directory {dir:?}"))?;
+        loop {
+            let mut next_id = 1;
+            for entry in
+                std::fs::read_dir(&dir).with_context(|| format!("Failed to list {dir:?}"))?
+            {
+                let entry = entry.with_context(|| format!("Failed to list {dir:?}"))?;
+                if let Some(id) = entry
+                    .file_name()
+                    .to_str()
+                    .and_then(|name| name.parse::<u64>().ok())
+                {
+                    next_id = next_id.max(id + 1);
+                }
+            }
+            let revision_id = next_id.to_string();
+            // Creating the directory is atomic, so concurrent imports cannot get the same id
+            match std::fs::create_dir(dir.join(&revision_id)) {
+                Ok(()) => return Ok(revision_id),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => Err(e)
+                    .with_context(|| format!("Failed to create revision directory in {dir:?}"))?,
+            }
+        }
+    }
+
+    pub fn save_revision(
+        &self,
+        problem_id: &str,
+        revision_id: &str,
+        revision: &config::ProblemRevision,
+        archive: &Archive,
+    ) -> Result<()> {
+        let dir = self.get_revision_dir(problem_id, revision_id)?;
+
+        // The archive is written first, so that the presence of the revision file implies that
+        // the revision is complete
+        let archive =
+            rmp_serde::to_vec_named(archive).context("Failed to serialize archive manifest")?;
+        write_atomically(&dir.join("archive.msgpack"), &archive)?;
+
+        let revision =
+            rmp_serde::to_vec_named(revision).context("Failed to serialize problem revision")?;
+        write_atomically(&dir.join("revision.msgpack"), &revision)?;
+
+        Ok(())
+    }
+
+    pub fn load_revision(
+        &self,
+        problem_id: &str,
+        revision_id: &str,
+    ) -> Result<(config::ProblemRevision, Archive)> {
+        let dir = self.get_revision_dir(problem_id, revision_id)?;
+
+        let revision = std::fs::read(dir.join("revision.msgpack")).with_context(|| {
+            format!("Revision {revision_id} of problem {problem_id} does not exist")
+        })?;
+        let revision = rmp_serde::from_slice(&revision).with_context(|| {
+            format!("Revision {revision_id} of problem {problem_id} is corrupted")
+        })?;
+
+        let archive = std::fs::read(dir.join("archive.msgpack")).with_context(|| {
+            format!("Archive of revision {revision_id} of problem {problem_id} does not exist")
+        })?;
+        let archive = rmp_serde::from_slice(&archive).with_context(|| {
+            format!("Archive of revision {revision_id} of problem {problem_id} is corrupted")
+        })?;
+
+        Ok((revision, archive))
+    }
+}
+
+// Writes to a temporary file next to the target first and then moves it into place, so that a crash
+// or a concurrent reader never observes a partially written file. The directory is synced too, so
+// that the new file survives a crash once this returns.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<()> {
+    let parent = path.parent().context("Path has no parent")?;
+    std::fs::create_dir_all(parent)
+        .with_context(|| format!("Failed to create directory {parent:?}"))?;
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        NEXT_TEMP_ID.fetch_add(1, Ordering::Relaxed)
+    ));
+    let temp_path = PathBuf::from(temp_path);
+    let result: Result<()> = try {
+        let mut file = std::fs::File::create(&temp_path)
+            .with_context(|| format!("Failed to create {temp_path:?}"))?;
+        file.write_all(data)
+            .with_context(|| format!("Failed to write to {temp_path:?}"))?;
+        file.sync_all()
+            .with_context(|| format!("Failed to sync {temp_path:?}"))?;
+        std::fs::rename(&temp_path, path)
+            .with_context(|| format!("Failed to move {temp_path:?} to {path:?}"))?;
+    };
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result?;
+
+    std::fs::File::open(parent)
+        .and_then(|directory| directory.sync_all())
+        .with_context(|| format!("Failed to sync directory {parent:?}"))
+}
+
+fn check_identifier(id: &str) -> Result<()> {
+    if id.is_empty()
+        || id.starts_with('.')
+        || !id
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, b'-' | b'_' | b'.'))
+    {
+        bail!(
+            "Invalid identifier {id:?}: it must be non-empty, consist of alphanumeric characters, \
+             '-', '_', and '.', and not start with '.'"
+        );
+    }
+    Ok(())
+}
+
+impl BlobHandle {
+    pub fn get_hash(&self) -> &str {
+        &self.hash
+    }
+
+    pub fn get_size(&self) -> u64 {
+        self.size
     }
 }
 
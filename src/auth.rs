@@ -0,0 +1,41 @@
+use crate::config;
+use anyhow::{bail, Context, Result};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+
+// The invoker proves that it knows the key by replying to a random nonce with
+// HMAC-SHA256(key, nonce || invoker name). The key itself never goes over the wire, and including
+// the name prevents a response from being replayed under a different identity.
+
+pub fn generate_nonce() -> Vec<u8> {
+    let mut nonce = vec![0; 32];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    nonce
+}
+
+pub fn get_invoker_key<'a>(config: &'a config::Config, invoker_name: &str) -> Result<&'a str> {
+    config
+        .auth
+        .invoker_keys
+        .get(invoker_name)
+        .or(config.auth.preshared_key.as_ref())
+        .map(|key| key.as_ref())
+        .with_context(|| {
+            format!(
+                "No key is configured for invoker {invoker_name:?}, either in auth.invoker_keys \
+                 or in auth.preshared_key"
+            )
+        })
+}
+
+pub fn verify_response(key: &str, nonce: &[u8], invoker_name: &str, mac: &[u8]) -> Result<()> {
+    let mut expected =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).context("Failed to initialize HMAC")?;
+    expected.update(nonce);
+    expected.update(invoker_name.as_bytes());
+    if expected.verify_slice(mac).is_err() {
+        bail!("Invoker {invoker_name:?} failed to authenticate: challenge response is invalid");
+    }
+    Ok(())
+}
@@ -1,50 +1,556 @@
-use crate::{invoker, message};
-use anyhow::{anyhow, Context, Result};
-use futures_util::stream::StreamExt;
+use crate::{
+    archive_store, auth, config, frontend, invoker, journal, message,
+    problem::{self, registry},
+    scheduler, submission, tls,
+};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio_rustls::rustls;
 use tokio_tungstenite::tungstenite;
 
-pub struct Conductor {}
+pub struct Conductor {
+    pub config: config::Config,
+    pub archive_store: archive_store::ArchiveStore,
+    pub problem_registry: registry::ProblemRegistry,
+    invokers: Mutex<HashMap<String, Arc<invoker::Invoker>>>,
+    pub scheduler: scheduler::Scheduler,
+    next_submission_id: AtomicU64,
+    shutting_down: AtomicBool,
+}
 
 impl Conductor {
-    pub async fn accept_invoker_connection(&self, socket: TcpStream) {
+    // Restores the submissions that were queued or being judged before the previous shutdown
+    pub fn new(config: config::Config) -> Result<Self> {
+        let data_root = Path::new(&config.data.problems);
+        let (journal, records) =
+            journal::Journal::open(&data_root.join("journal"), &data_root.join("checkpoints"))?;
+        let checkpoints = journal.list_checkpoints()?;
+
+        // Submission ids are never reused
+        let next_submission_id = records
+            .iter()
+            .filter_map(|record| match record {
+                journal::Record::Added { submission_id, .. } => Some(submission_id),
+                _ => None,
+            })
+            .chain(
+                checkpoints
+                    .iter()
+                    .map(|checkpoint| &checkpoint.submission_id),
+            )
+            .filter_map(|submission_id| submission_id.parse::<u64>().ok())
+            .max()
+            .map_or(1, |id| id + 1);
+
+        let conductor = Self {
+            archive_store: archive_store::ArchiveStore::new(config.data.problems.clone().into()),
+            problem_registry: registry::ProblemRegistry::new(),
+            invokers: Mutex::new(HashMap::new()),
+            scheduler: scheduler::Scheduler::new(journal, config.scheduler.clone()),
+            config,
+            next_submission_id: AtomicU64::new(next_submission_id),
+            shutting_down: AtomicBool::new(false),
+        };
+        conductor
+            .scheduler
+            .replay_journal(records, checkpoints, |problem_id, revision_id| {
+                conductor.get_problem_revision(problem_id, revision_id)
+            });
+        Ok(conductor)
+    }
+
+    // If the connection is over TLS with client authentication, client_certificate is the verified
+    // certificate of the invoker
+    pub async fn accept_invoker_connection<S: AsyncRead + AsyncWrite + Unpin>(
+        &'static self,
+        socket: S,
+        client_certificate: Option<rustls::Certificate>,
+    ) {
+        let mut invoker_object: Option<Arc<invoker::Invoker>> = None;
+        // The handshake and the nonce the invoker has to sign before it is registered
+        let mut pending_handshake: Option<(message::i2c::Handshake, Vec<u8>)> = None;
+
         let result: Result<()> = try {
-            let mut stream = tokio_tungstenite::accept_async(socket)
+            let stream = tokio_tungstenite::accept_async(socket)
                 .await
                 .context("Failure during websocket handshake")?;
 
-            let mut invoker_object = None;
-
-            while let Some(message) = stream.next().await {
-                let message = message.context("Failed to read message from the invoker")?;
-                match message {
-                    tungstenite::Message::Close(_) => break,
-                    tungstenite::Message::Binary(buf) => {
-                        let message = rmp_serde::from_slice(&buf)
-                            .context("Failed to parse buffer as msgpack format")?;
-                        match invoker_object {
-                            None => {
-                                if let message::i2c::Message::Handshake(handshake) = message {
-                                    invoker_object = Some(invoker::Invoker::new(handshake));
-                                } else {
-                                    Err(anyhow!("The first message of the invoker was not a handshake, but {message:?}"))?;
+            let (mut sink, mut source) = stream.split();
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+
+            // Any message, including pongs, proves that the invoker is alive
+            let last_activity = Mutex::new(Instant::now());
+            let heartbeat_interval = Duration::from_millis(self.config.heartbeat.interval_ms);
+            let heartbeat_timeout = Duration::from_millis(self.config.heartbeat.timeout_ms);
+
+            let reader = async {
+                while let Some(message) = source.next().await {
+                    let message = message.context("Failed to read message from the invoker")?;
+                    *last_activity.lock().unwrap() = Instant::now();
+                    match message {
+                        tungstenite::Message::Close(_) => break,
+                        tungstenite::Message::Binary(buf) => {
+                            let message = rmp_serde::from_slice(&buf)
+                                .context("Failed to parse buffer as msgpack format")?;
+                            match invoker_object {
+                                None => {
+                                    let result = self.handle_handshake_message(
+                                        &mut pending_handshake,
+                                        message,
+                                        &sender,
+                                        &client_certificate,
+                                    );
+                                    match result {
+                                        Ok(registered_invoker) => {
+                                            invoker_object = registered_invoker;
+                                        }
+                                        Err(e) => {
+                                            // Tell the invoker why it was rejected before closing
+                                            // the connection
+                                            let _ = sender.send(
+                                                message::c2i::Message::HandshakeResult(
+                                                    message::c2i::HandshakeResult {
+                                                        result: Err(format!("{e:#}")),
+                                                    },
+                                                ),
+                                            );
+                                            return Err(e);
+                                        }
+                                    }
+                                }
+                                Some(ref invoker_object) => {
+                                    invoker_object.handle_message(message).await?;
                                 }
                             }
-                            Some(ref invoker_object) => {
-                                invoker_object.handle_message(message).await?;
+                        }
+                        tungstenite::Message::Ping(_) => (),
+                        // Replies to the heartbeat pings, already counted as activity above
+                        tungstenite::Message::Pong(_) => (),
+                        _ => {
+                            println!(
+                                "Message of unknown type received from the invoker: {message:?}"
+                            )
+                        }
+                    };
+                }
+                Ok(())
+            };
+
+            let writer = async {
+                let mut heartbeat = tokio::time::interval(heartbeat_interval);
+                loop {
+                    tokio::select! {
+                        message = receiver.recv() => {
+                            let Some(message) = message else {
+                                break;
+                            };
+                            let buf = rmp_serde::to_vec(&message).with_context(|| {
+                                format!("Failed to serialize {message:?} to msgpack")
+                            })?;
+                            sink.send(tungstenite::Message::Binary(buf))
+                                .await
+                                .context("Failed to send message to the invoker")?;
+                        }
+                        _ = heartbeat.tick() => {
+                            let silence = last_activity.lock().unwrap().elapsed();
+                            if silence > heartbeat_timeout {
+                                bail!(
+                                    "The invoker has not responded for {silence:?}, considering \
+                                     it dead"
+                                );
                             }
+                            sink.send(tungstenite::Message::Ping(Vec::new()))
+                                .await
+                                .context("Failed to send ping to the invoker")?;
                         }
                     }
-                    tungstenite::Message::Ping(_) => (),
-                    _ => {
-                        println!("Message of unknown type received from the invoker: {message:?}")
+                }
+                Ok(())
+            };
+
+            let result: Result<()> = tokio::select! {
+                result = reader => result,
+                result = writer => result,
+            };
+
+            // Whatever happened, try to deliver the remaining messages, e.g. the reason of a
+            // rejection, and close the connection gracefully. A dead invoker may never read them,
+            // so don't wait for too long.
+            let _ = tokio::time::timeout(heartbeat_timeout, async {
+                while let Ok(message) = receiver.try_recv() {
+                    let Ok(buf) = rmp_serde::to_vec(&message) else {
+                        continue;
+                    };
+                    if sink.send(tungstenite::Message::Binary(buf)).await.is_err() {
+                        break;
                     }
-                };
-            }
+                }
+                let _ = sink.close().await;
+            })
+            .await;
+
+            result?;
         };
 
+        if let Some(invoker_object) = invoker_object {
+            self.unregister_invoker(&invoker_object);
+        }
+
         if let Err(e) = result {
             println!("Invoker connection errored: {e:?}");
         }
     }
+
+    // Administrative requests are only handled if the connection came through the admin listener
+    pub async fn accept_frontend_connection(&'static self, socket: TcpStream, admin: bool) {
+        let result: Result<()> = try {
+            let stream = tokio_tungstenite::accept_async(socket)
+                .await
+                .context("Failure during websocket handshake")?;
+
+            let (mut sink, mut source) = stream.split();
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+
+            let frontend_object = frontend::Frontend::new(self, sender, admin);
+
+            let reader = async {
+                while let Some(message) = source.next().await {
+                    let message = message.context("Failed to read message from the frontend")?;
+                    match message {
+                        tungstenite::Message::Close(_) => break,
+                        tungstenite::Message::Binary(buf) => {
+                            let message = rmp_serde::from_slice(&buf)
+                                .context("Failed to parse buffer as msgpack format")?;
+                            frontend_object.handle_message(message).await?;
+                        }
+                        tungstenite::Message::Ping(_) => (),
+                        _ => {
+                            println!(
+                                "Message of unknown type received from the frontend: {message:?}"
+                            )
+                        }
+                    };
+                }
+                Ok(())
+            };
+
+            let writer = async {
+                while let Some(message) = receiver.recv().await {
+                    let buf = rmp_serde::to_vec(&message)
+                        .with_context(|| format!("Failed to serialize {message:?} to msgpack"))?;
+                    sink.send(tungstenite::Message::Binary(buf))
+                        .await
+                        .context("Failed to send message to the frontend")?;
+                }
+                Ok(())
+            };
+
+            let result: Result<()> = tokio::select! {
+                result = reader => result,
+                result = writer => result,
+            };
+
+            let _ = sink.close().await;
+
+            result?;
+        };
+
+        if let Err(e) = result {
+            println!("Frontend connection errored: {e:?}");
+        }
+    }
+
+    // Handles a message received before the invoker is registered. Returns the invoker object once
+    // the handshake is complete.
+    fn handle_handshake_message(
+        &'static self,
+        pending_handshake: &mut Option<(message::i2c::Handshake, Vec<u8>)>,
+        message: message::i2c::Message,
+        sender: &mpsc::UnboundedSender<message::c2i::Message>,
+        client_certificate: &Option<rustls::Certificate>,
+    ) -> Result<Option<Arc<invoker::Invoker>>> {
+        match (pending_handshake.take(), message) {
+            (None, message::i2c::Message::Handshake(handshake)) => {
+                if handshake.protocol_version != message::PROTOCOL_VERSION {
+                    bail!(
+                        "Invoker {:?} speaks protocol version {}, but the conductor only supports \
+                         version {}",
+                        handshake.invoker_name,
+                        handshake.protocol_version,
+                        message::PROTOCOL_VERSION
+                    );
+                }
+                if let Some(ref certificate) = client_certificate {
+                    tls::verify_invoker_certificate(certificate, &handshake.invoker_name)?;
+                }
+                // Make sure the invoker is known before doing any work
+                auth::get_invoker_key(&self.config, &handshake.invoker_name)?;
+                let nonce = auth::generate_nonce();
+                sender
+                    .send(message::c2i::Message::Challenge(message::c2i::Challenge {
+                        nonce: nonce.clone(),
+                    }))
+                    .ok()
+                    .context("Failed to send challenge to the invoker")?;
+                *pending_handshake = Some((handshake, nonce));
+                Ok(None)
+            }
+            (Some((handshake, nonce)), message::i2c::Message::ChallengeResponse(response)) => {
+                auth::verify_response(
+                    auth::get_invoker_key(&self.config, &handshake.invoker_name)?,
+                    &nonce,
+                    &handshake.invoker_name,
+                    &response.mac,
+                )?;
+                let invoker_object = self.register_invoker(handshake, sender.clone())?;
+                invoker_object.send_to_invoker(message::c2i::Message::HandshakeResult(
+                    message::c2i::HandshakeResult {
+                        result: Ok(message::c2i::NegotiatedFeatures {
+                            capabilities: invoker_object.get_capabilities(),
+                        }),
+                    },
+                ))?;
+                Ok(Some(invoker_object))
+            }
+            (None, message) => {
+                bail!("The first message of the invoker was not a handshake, but {message:?}");
+            }
+            (Some(_), message) => {
+                bail!("The invoker was expected to respond to the challenge, but sent {message:?}");
+            }
+        }
+    }
+
+    fn register_invoker(
+        &'static self,
+        handshake: message::i2c::Handshake,
+        sender: mpsc::UnboundedSender<message::c2i::Message>,
+    ) -> Result<Arc<invoker::Invoker>> {
+        let mut invokers = self.invokers.lock().unwrap();
+        if invokers.contains_key(&handshake.invoker_name) {
+            bail!(
+                "An invoker named {:?} is already connected",
+                handshake.invoker_name
+            );
+        }
+        let invoker_object = Arc::new(invoker::Invoker::new(self, handshake, sender));
+        invokers.insert(
+            invoker_object.get_name().to_string(),
+            invoker_object.clone(),
+        );
+        println!("Invoker {} connected", invoker_object.get_name());
+        Ok(invoker_object)
+    }
+
+    fn unregister_invoker(&self, invoker_object: &invoker::Invoker) {
+        self.invokers
+            .lock()
+            .unwrap()
+            .remove(invoker_object.get_name());
+        // Whatever the invoker was doing has to be redone elsewhere
+        self.scheduler
+            .handle_invoker_lost(invoker_object.get_name());
+        self.schedule();
+        println!("Invoker {} disconnected", invoker_object.get_name());
+    }
+
+    pub fn get_invokers(&self) -> Vec<Arc<invoker::Invoker>> {
+        self.invokers.lock().unwrap().values().cloned().collect()
+    }
+
+    pub fn get_problem_revision(
+        &self,
+        problem_id: &str,
+        revision_id: &str,
+    ) -> Result<Arc<problem::config::ProblemRevision>> {
+        self.problem_registry
+            .get_revision(&self.archive_store, problem_id, revision_id)
+    }
+
+    // Queues a submission for the latest revision of the problem and returns the submission id
+    pub async fn add_submission(
+        &self,
+        problem_id: String,
+        language: String,
+        files: HashMap<String, Vec<u8>>,
+        priority: submission::Priority,
+        owner: submission::Owner,
+    ) -> Result<String> {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            bail!("The conductor is shutting down and does not accept new submissions");
+        }
+
+        let revision_id = self
+            .problem_registry
+            .get_latest_revision_id(&self.archive_store, &problem_id)?;
+        let problem = self.get_problem_revision(&problem_id, &revision_id)?;
+
+        let submission_id = self.allocate_submission_id();
+
+        let mut submission = submission::Submission::new(
+            submission_id.clone(),
+            problem_id,
+            revision_id,
+            problem,
+            language,
+            files,
+        );
+        submission.priority = priority;
+        submission.owner = owner;
+        self.scheduler.add_submission(submission).await?;
+        self.schedule();
+
+        Ok(submission_id)
+    }
+
+    // Confirms the subscription and streams the events of the submission to the subscriber
+    pub fn subscribe(
+        &self,
+        request_id: u64,
+        submission_id: &str,
+        subscriber: mpsc::UnboundedSender<message::c2f::Message>,
+    ) -> Result<()> {
+        self.scheduler.subscribe(
+            request_id,
+            submission_id,
+            subscriber,
+            |problem_id, revision_id| self.get_problem_revision(problem_id, revision_id),
+        )
+    }
+
+    // Queues rejudges of the targeted submissions against the given revision, or against the latest
+    // revision of the problem if none is given. Returns (original id, rejudge id) pairs.
+    pub async fn rejudge(
+        &self,
+        target: message::f2c::RejudgeTarget,
+        revision_id: Option<String>,
+        tests: Option<Vec<u64>>,
+    ) -> (Vec<(String, String)>, Result<()>) {
+        if self.shutting_down.load(Ordering::Relaxed) {
+            return (
+                Vec::new(),
+                Err(anyhow!(
+                    "The conductor is shutting down and does not accept new submissions"
+                )),
+            );
+        }
+
+        let original_ids = match target {
+            message::f2c::RejudgeTarget::Submissions(submission_ids) => submission_ids,
+            message::f2c::RejudgeTarget::Problem(problem_id) => {
+                self.scheduler.get_submissions_of_problem(&problem_id)
+            }
+        };
+
+        let mut rejudged = Vec::new();
+        let result: Result<()> = try {
+            for original_id in original_ids {
+                let (problem_id, language, files) = self
+                    .scheduler
+                    .get_submission_source(&original_id, |problem_id, revision_id| {
+                        self.get_problem_revision(problem_id, revision_id)
+                    })?;
+                let revision_id = match revision_id {
+                    Some(ref revision_id) => revision_id.clone(),
+                    None => self
+                        .problem_registry
+                        .get_latest_revision_id(&self.archive_store, &problem_id)?,
+                };
+                let problem = self.get_problem_revision(&problem_id, &revision_id)?;
+
+                let mut submission = submission::Submission::new(
+                    self.allocate_submission_id(),
+                    problem_id,
+                    revision_id,
+                    problem,
+                    language,
+                    files,
+                );
+                submission.rejudge_of = Some(original_id.clone());
+                submission.priority = submission::Priority::Rejudge;
+                let submission_id = submission.id.clone();
+                self.scheduler
+                    .add_rejudge(submission, tests.as_deref(), |problem_id, revision_id| {
+                        self.get_problem_revision(problem_id, revision_id)
+                    })
+                    .await
+                    .with_context(|| format!("Failed to rejudge submission {original_id}"))?;
+                rejudged.push((original_id, submission_id));
+            }
+        };
+        // Whatever was queued before a failure is judged anyway
+        self.schedule();
+        (rejudged, result)
+    }
+
+    fn allocate_submission_id(&self) -> String {
+        self.next_submission_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string()
+    }
+
+    pub fn set_invoker_draining(&self, invoker_name: &str, draining: bool) -> Result<()> {
+        self.invokers
+            .lock()
+            .unwrap()
+            .get(invoker_name)
+            .with_context(|| format!("Invoker {invoker_name:?} is not connected"))?
+            .set_draining(draining);
+        // Pending submissions may now be started on the invoker
+        self.schedule();
+        Ok(())
+    }
+
+    pub fn lift_invoker_quarantine(&self, invoker_name: &str) -> Result<()> {
+        self.invokers
+            .lock()
+            .unwrap()
+            .get(invoker_name)
+            .with_context(|| format!("Invoker {invoker_name:?} is not connected"))?
+            .lift_quarantine();
+        self.schedule();
+        Ok(())
+    }
+
+    // Stops accepting new submissions and starting queued ones, and waits until the ones already on
+    // invokers are finalized. Queued submissions are resumed from the journal on the next start.
+    // Returns false if that did not happen before the timeout.
+    pub async fn shutdown(&self, timeout: Duration) -> bool {
+        self.shutting_down.store(true, Ordering::Relaxed);
+        self.scheduler.stop();
+        let finished = tokio::time::timeout(timeout, async {
+            while self.scheduler.has_submissions_on_invokers() {
+                tokio::time::sleep(Duration::from_millis(500)).await;
+            }
+        })
+        .await
+        .is_ok();
+        if let Err(e) = self.scheduler.flush_journal().await {
+            println!("Failed to flush the journal: {e:?}");
+        }
+        finished
+    }
+
+    // Periodically fails the work that invokers did not report on in time
+    pub async fn run_watchdog(&'static self) {
+        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            interval.tick().await;
+            self.scheduler.expire_compilations(&self.get_invokers());
+            self.scheduler.evict_finalized();
+            self.schedule();
+        }
+    }
+
+    pub fn schedule(&self) {
+        self.scheduler.schedule(&self.get_invokers());
+    }
 }
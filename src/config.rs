@@ -1,17 +1,146 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 
 #[derive(Deserialize)]
 pub struct Config {
     pub listen: ListenConfig,
     pub data: DataConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub heartbeat: HeartbeatConfig,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    #[serde(default)]
+    pub scheduler: SchedulerConfig,
 }
 
 #[derive(Deserialize)]
 pub struct ListenConfig {
     pub invokers: String,
+    // If present, invokers have to connect over wss://
+    pub invokers_tls: Option<TlsConfig>,
+    pub frontend: String,
+    // Frontends connected here may also manage invokers and rejudge submissions, so this address
+    // must not be reachable by contestants. Administrative requests are disabled if it is absent.
+    pub admin: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct TlsConfig {
+    // Paths to PEM files
+    pub certificate: String,
+    pub key: String,
+    // If present, invokers must present a client certificate signed by this CA and issued for their
+    // name
+    pub client_ca: Option<String>,
 }
 
 #[derive(Deserialize)]
 pub struct DataConfig {
     pub problems: String,
 }
+
+#[derive(Default, Deserialize)]
+pub struct AuthConfig {
+    // Used by invokers that do not have a key of their own
+    pub preshared_key: Option<String>,
+    #[serde(default)]
+    pub invoker_keys: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct HeartbeatConfig {
+    pub interval_ms: u64,
+    // An invoker that sends nothing, not even a pong, for this long is considered dead
+    pub timeout_ms: u64,
+}
+
+impl Default for HeartbeatConfig {
+    fn default() -> Self {
+        Self {
+            interval_ms: 5000,
+            timeout_ms: 30000,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct ShutdownConfig {
+    // How long to wait for submissions in progress to be judged after SIGTERM or SIGINT
+    pub timeout_ms: u64,
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self { timeout_ms: 60000 }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct SchedulerConfig {
+    // A queued submission is promoted by one priority class every time it waits this long
+    pub aging_interval_ms: u64,
+    // Relative shares of cores, 1 if absent. The share of a submission's owner is the product of
+    // the weights of its contest and its user.
+    pub contest_weights: HashMap<String, u64>,
+    pub user_weights: HashMap<String, u64>,
+    pub max_compilations_per_invoker: u64,
+    // A compilation that is not reported to be finished in this time is considered failed
+    pub compilation_timeout_ms: u64,
+    // Labels an invoker has to advertise to judge submissions to the problem or in the language
+    pub problem_labels: HashMap<String, Vec<String>>,
+    pub language_labels: HashMap<String, Vec<String>>,
+    // How many times a submission is moved to another invoker after an infrastructure fault before
+    // it is failed
+    pub max_retries: u64,
+    pub quarantine_after_failures: u64,
+    pub tle_recheck: TleRecheckConfig,
+    // How long a finalized submission is kept in memory. After that, it is loaded from its
+    // checkpoint on demand.
+    pub finalized_retention_ms: u64,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            aging_interval_ms: 300000,
+            contest_weights: HashMap::new(),
+            user_weights: HashMap::new(),
+            max_compilations_per_invoker: 2,
+            compilation_timeout_ms: 120000,
+            problem_labels: HashMap::new(),
+            language_labels: HashMap::new(),
+            max_retries: 2,
+            quarantine_after_failures: 3,
+            tle_recheck: TleRecheckConfig::default(),
+            finalized_retention_ms: 600000,
+        }
+    }
+}
+
+// Tests that exceed the time limit only slightly are rerun, and the fastest run determines the
+// verdict, so that timing noise does not turn into TLE verdicts
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct TleRecheckConfig {
+    // How many times a borderline test is rerun, 0 disables rechecks
+    pub runs: u64,
+    // A TLE is borderline if the CPU time exceeds the limit by at most this many percent
+    pub margin_percent: u64,
+    // Move the submission to another invoker for the rerun, if there is a suitable one
+    pub different_invoker: bool,
+}
+
+impl Default for TleRecheckConfig {
+    fn default() -> Self {
+        Self {
+            runs: 0,
+            margin_percent: 10,
+            different_invoker: false,
+        }
+    }
+}
@@ -1,6 +1,6 @@
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum Error {
     InvokerFailure(String),
     ConductorFailure(String),
@@ -11,6 +11,14 @@ pub enum Error {
 
 pub use Error::*;
 
+impl Error {
+    // Infrastructure faults that say nothing about the submission, so it can be judged again
+    // elsewhere
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, InvokerFailure(_) | CommunicationError(_))
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{:?}", self)
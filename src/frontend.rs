@@ -0,0 +1,170 @@
+use crate::{conductor, errors, message};
+use anyhow::{bail, Context, Result};
+use tokio::sync::mpsc;
+
+pub struct Frontend {
+    conductor: &'static conductor::Conductor,
+    sender: mpsc::UnboundedSender<message::c2f::Message>,
+    admin: bool,
+}
+
+impl Frontend {
+    pub fn new(
+        conductor: &'static conductor::Conductor,
+        sender: mpsc::UnboundedSender<message::c2f::Message>,
+        admin: bool,
+    ) -> Frontend {
+        Frontend {
+            conductor,
+            sender,
+            admin,
+        }
+    }
+
+    pub fn send_to_frontend(&self, message: message::c2f::Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .ok()
+            .context("Connection to the frontend is closed")
+    }
+
+    pub async fn handle_message(&self, message: message::f2c::Message) -> Result<()> {
+        use message::f2c::Message::*;
+        if !self.admin
+            && matches!(
+                message,
+                SetInvokerDraining(_) | ListInvokers(_) | Rejudge(_) | LiftInvokerQuarantine(_)
+            )
+        {
+            bail!("Administrative requests are only accepted on the admin listener");
+        }
+        match message {
+            Submit(message) => self.submit(message).await,
+            Subscribe(message) => self.subscribe(message).await,
+            SetInvokerDraining(message) => self.set_invoker_draining(message).await,
+            ListInvokers(message) => self.list_invokers(message).await,
+            Rejudge(message) => self.rejudge(message).await,
+            LiftInvokerQuarantine(message) => self.lift_invoker_quarantine(message).await,
+        }
+    }
+
+    async fn submit(&self, message: message::f2c::Submit) -> Result<()> {
+        let result = self
+            .conductor
+            .add_submission(
+                message.problem_id,
+                message.language,
+                message.files,
+                message.priority,
+                message.owner,
+            )
+            .await
+            .map_err(|e| errors::UserFailure(format!("{e:#}")));
+        self.send_to_frontend(message::c2f::Message::Submitted(message::c2f::Submitted {
+            request_id: message.request_id,
+            result,
+        }))
+    }
+
+    async fn subscribe(&self, message: message::f2c::Subscribe) -> Result<()> {
+        let result = self.conductor.subscribe(
+            message.request_id,
+            &message.submission_id,
+            self.sender.clone(),
+        );
+        if let Err(e) = result {
+            self.send_to_frontend(message::c2f::Message::Subscribed(
+                message::c2f::Subscribed {
+                    request_id: message.request_id,
+                    result: Err(errors::UserFailure(format!("{e:#}"))),
+                },
+            ))?;
+        }
+        Ok(())
+    }
+
+    async fn rejudge(&self, message: message::f2c::Rejudge) -> Result<()> {
+        let (rejudged, result) = self
+            .conductor
+            .rejudge(message.target, message.revision_id, message.tests)
+            .await;
+        self.send_to_frontend(message::c2f::Message::Rejudged(message::c2f::Rejudged {
+            request_id: message.request_id,
+            submissions: rejudged
+                .into_iter()
+                .map(
+                    |(original_id, submission_id)| message::c2f::RejudgedSubmission {
+                        original_id,
+                        submission_id,
+                    },
+                )
+                .collect(),
+            result: result.map_err(|e| errors::UserFailure(format!("{e:#}"))),
+        }))
+    }
+
+    async fn set_invoker_draining(&self, message: message::f2c::SetInvokerDraining) -> Result<()> {
+        let result = self
+            .conductor
+            .set_invoker_draining(&message.invoker_name, message.draining)
+            .map_err(|e| errors::UserFailure(format!("{e:#}")));
+        self.send_to_frontend(message::c2f::Message::Acknowledged(
+            message::c2f::Acknowledged {
+                request_id: message.request_id,
+                result,
+            },
+        ))
+    }
+
+    async fn lift_invoker_quarantine(
+        &self,
+        message: message::f2c::LiftInvokerQuarantine,
+    ) -> Result<()> {
+        let result = self
+            .conductor
+            .lift_invoker_quarantine(&message.invoker_name)
+            .map_err(|e| errors::UserFailure(format!("{e:#}")));
+        self.send_to_frontend(message::c2f::Message::Acknowledged(
+            message::c2f::Acknowledged {
+                request_id: message.request_id,
+                result,
+            },
+        ))
+    }
+
+    async fn list_invokers(&self, message: message::f2c::ListInvokers) -> Result<()> {
+        let mut invokers: Vec<message::c2f::InvokerInfo> = self
+            .conductor
+            .get_invokers()
+            .into_iter()
+            .map(|invoker| {
+                let state = invoker.get_state();
+                let mut cores: Vec<u64> = state.cores.into_iter().collect();
+                cores.sort_unstable();
+                message::c2f::InvokerInfo {
+                    name: invoker.get_name().to_string(),
+                    labels: invoker.get_labels(),
+                    cores,
+                    busy_cores: self
+                        .conductor
+                        .scheduler
+                        .get_busy_core_count(invoker.get_name()),
+                    designated_ram: state.designated_ram,
+                    committed_ram: self
+                        .conductor
+                        .scheduler
+                        .get_committed_ram(invoker.get_name()),
+                    draining: state.draining,
+                    quarantined: state.quarantined,
+                }
+            })
+            .collect();
+        invokers.sort_by(|a, b| a.name.cmp(&b.name));
+        self.send_to_frontend(message::c2f::Message::InvokerList(
+            message::c2f::InvokerList {
+                request_id: message.request_id,
+                invokers,
+            },
+        ))
+    }
+}
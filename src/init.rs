@@ -1,13 +1,32 @@
-use crate::{conductor, config};
+use crate::{archive_store, conductor, config, polygon::package, problem::registry, tls};
 use anyhow::{Context, Result};
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::Path;
+use std::time::Duration;
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 pub struct CLIArgs {
-    #[clap(short, long)]
+    // Global, so that it may also follow the subcommand
+    #[clap(short, long, global = true)]
     pub config: String,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Import a Polygon package as a new revision of a problem and print the revision id
+    ImportPolygon {
+        /// Path to the package: either a .zip archive or an unpacked directory
+        package: String,
+
+        #[clap(long)]
+        problem_id: String,
+    },
 }
 
 pub async fn main() -> Result<()> {
@@ -18,6 +37,39 @@ pub async fn main() -> Result<()> {
 
     let config: config::Config = toml::from_str(&config).context("Config is invalid")?;
 
+    // Errors are returned from main, which prints the whole context chain and exits with a non-zero
+    // code
+    match cli_args.command {
+        Some(Command::ImportPolygon {
+            package,
+            problem_id,
+        }) => import_polygon(config, &package, &problem_id),
+        None => serve(config).await,
+    }
+}
+
+fn import_polygon(config: config::Config, package: &str, problem_id: &str) -> Result<()> {
+    let archive_store = archive_store::ArchiveStore::new(config.data.problems.into());
+    let problem_registry = registry::ProblemRegistry::new();
+
+    let package_path = Path::new(package);
+    let revision_id = if package_path.is_dir() {
+        let package = package::DirectoryPackage::open(package_path)?;
+        problem_registry.import_polygon(&archive_store, &|path| package.read_file(path), problem_id)
+    } else {
+        let package = package::ZipPackage::open(package_path)?;
+        problem_registry.import_polygon(&archive_store, &|path| package.read_file(path), problem_id)
+    }
+    .with_context(|| {
+        format!("Failed to import Polygon package {package} as problem {problem_id}")
+    })?;
+
+    println!("{revision_id}");
+
+    Ok(())
+}
+
+async fn serve(config: config::Config) -> Result<()> {
     let invoker_server = TcpListener::bind(config.listen.invokers.clone())
         .await
         .with_context(|| {
@@ -28,10 +80,98 @@ pub async fn main() -> Result<()> {
             )
         })?;
 
-    let conductor = Box::leak(Box::new(conductor::Conductor {}));
+    let frontend_server = TcpListener::bind(config.listen.frontend.clone())
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to listen on {:?} (this address is from field listen.frontend of the \
+                 configuration file)",
+                config.listen.frontend
+            )
+        })?;
+
+    let admin_server = match config.listen.admin {
+        Some(ref address) => Some(TcpListener::bind(address).await.with_context(|| {
+            format!(
+                "Failed to listen on {address:?} (this address is from field listen.admin of the \
+                 configuration file)"
+            )
+        })?),
+        None => None,
+    };
+
+    let invoker_tls_acceptor = config
+        .listen
+        .invokers_tls
+        .as_ref()
+        .map(tls::make_acceptor)
+        .transpose()
+        .context("Failed to configure TLS (listen.invokers_tls in the configuration file)")?;
+
+    let conductor: &conductor::Conductor = Box::leak(Box::new(
+        conductor::Conductor::new(config).context("Failed to restore the judgement queue")?,
+    ));
+
+    tokio::spawn(conductor.run_watchdog());
+
+    let mut sigterm = signal(SignalKind::terminate()).context("Failed to handle SIGTERM")?;
+    let mut sigint = signal(SignalKind::interrupt()).context("Failed to handle SIGINT")?;
 
     loop {
-        let (socket, _addr) = invoker_server.accept().await?;
-        tokio::spawn(conductor.accept_invoker_connection(socket));
+        tokio::select! {
+            _ = sigterm.recv() => break,
+            _ = sigint.recv() => break,
+            result = invoker_server.accept() => {
+                let (socket, _addr) = result?;
+                match invoker_tls_acceptor {
+                    None => {
+                        tokio::spawn(conductor.accept_invoker_connection(socket, None));
+                    }
+                    Some(ref acceptor) => {
+                        let acceptor = acceptor.clone();
+                        tokio::spawn(async move {
+                            match acceptor.accept(socket).await {
+                                Ok(stream) => {
+                                    let client_certificate = stream
+                                        .get_ref()
+                                        .1
+                                        .peer_certificates()
+                                        .and_then(|certificates| certificates.first().cloned());
+                                    conductor
+                                        .accept_invoker_connection(stream, client_certificate)
+                                        .await;
+                                }
+                                Err(e) => println!("TLS handshake with invoker failed: {e:?}"),
+                            }
+                        });
+                    }
+                }
+            }
+            result = frontend_server.accept() => {
+                let (socket, _addr) = result?;
+                tokio::spawn(conductor.accept_frontend_connection(socket, false));
+            }
+            result = async {
+                match admin_server {
+                    Some(ref server) => server.accept().await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                let (socket, _addr) = result?;
+                tokio::spawn(conductor.accept_frontend_connection(socket, true));
+            }
+        }
     }
+
+    println!("Shutting down: waiting for submissions on invokers to be judged");
+    if conductor
+        .shutdown(Duration::from_millis(conductor.config.shutdown.timeout_ms))
+        .await
+    {
+        println!("All submissions on invokers are judged, queued ones stay in the journal");
+    } else {
+        println!("Timed out waiting for submissions, exiting anyway");
+    }
+
+    Ok(())
 }
@@ -1,23 +1,137 @@
-use crate::message;
-use anyhow::{bail, Result};
+use crate::{conductor, errors, message};
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::io::Write;
+use std::sync::Mutex;
+use tokio::sync::mpsc;
+
+// Optional protocol features supported by the conductor
+const SUPPORTED_CAPABILITIES: &[&str] = &[
+    // Contents of SupplyFile are compressed with raw deflate
+    "deflate-supply-file",
+];
 
 pub struct Invoker {
+    conductor: &'static conductor::Conductor,
     name: String,
+    capabilities: HashSet<String>,
+    labels: HashSet<String>,
+    languages: HashSet<String>,
+    sender: mpsc::UnboundedSender<message::c2i::Message>,
+    state: Mutex<InvokerState>,
+}
+
+#[derive(Clone)]
+pub struct InvokerState {
+    pub cores: HashSet<u64>,
+    pub designated_ram: u64,
+    // Set by administrators to take the invoker out of rotation
+    pub draining: bool,
+    // Infrastructure faults since the last successfully judged compilation or test
+    pub failure_streak: u64,
+    // Set after too many faults in a row, until administrators lift it. Like a draining invoker, a
+    // quarantined one gets no new submissions.
+    pub quarantined: bool,
 }
 
 impl Invoker {
-    pub fn new(handshake: message::i2c::Handshake) -> Invoker {
+    pub fn new(
+        conductor: &'static conductor::Conductor,
+        handshake: message::i2c::Handshake,
+        sender: mpsc::UnboundedSender<message::c2i::Message>,
+    ) -> Invoker {
         Invoker {
+            conductor,
             name: handshake.invoker_name,
+            capabilities: handshake
+                .capabilities
+                .into_iter()
+                .filter(|capability| SUPPORTED_CAPABILITIES.contains(&capability.as_str()))
+                .collect(),
+            labels: handshake.labels.into_iter().collect(),
+            languages: handshake.languages.into_iter().collect(),
+            sender,
+            state: Mutex::new(InvokerState {
+                cores: HashSet::new(),
+                designated_ram: 0,
+                draining: false,
+                failure_streak: 0,
+                quarantined: false,
+            }),
         }
     }
 
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_capabilities(&self) -> Vec<String> {
+        self.capabilities.iter().cloned().collect()
+    }
+
+    pub fn has_capability(&self, capability: &str) -> bool {
+        self.capabilities.contains(capability)
+    }
+
+    pub fn get_labels(&self) -> Vec<String> {
+        let mut labels: Vec<String> = self.labels.iter().cloned().collect();
+        labels.sort();
+        labels
+    }
+
+    pub fn has_labels(&self, labels: &[String]) -> bool {
+        labels.iter().all(|label| self.labels.contains(label))
+    }
+
+    pub fn supports_language(&self, language: &str) -> bool {
+        self.languages.is_empty() || self.languages.contains(language)
+    }
+
+    pub fn get_state(&self) -> InvokerState {
+        self.state.lock().unwrap().clone()
+    }
+
+    pub fn set_draining(&self, draining: bool) {
+        self.state.lock().unwrap().draining = draining;
+    }
+
+    // Returns true if this failure put the invoker into quarantine
+    pub fn record_failure(&self, quarantine_after_failures: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.failure_streak += 1;
+        if !state.quarantined && state.failure_streak >= quarantine_after_failures {
+            state.quarantined = true;
+            return true;
+        }
+        false
+    }
+
+    pub fn record_success(&self) {
+        self.state.lock().unwrap().failure_streak = 0;
+    }
+
+    pub fn lift_quarantine(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.quarantined = false;
+        state.failure_streak = 0;
+    }
+
+    pub fn send_to_invoker(&self, message: message::c2i::Message) -> Result<()> {
+        self.sender
+            .send(message)
+            .ok()
+            .with_context(|| format!("Connection to invoker {} is closed", self.name))
+    }
+
     pub async fn handle_message(&self, message: message::i2c::Message) -> Result<()> {
         use message::i2c::Message::*;
         match message {
             Handshake(message) => {
                 bail!("Unexpected handshake in the middle of conversation: {message:?}");
             }
+            ChallengeResponse(message) => {
+                bail!("Unexpected challenge response in the middle of conversation: {message:?}");
+            }
             UpdateMode(message) => self.update_mode(message).await,
             NotifyCompilationStatus(message) => self.notify_compilation_status(message).await,
             NotifyTestStatus(message) => self.notify_test_status(message).await,
@@ -27,6 +141,21 @@ impl Invoker {
     }
 
     async fn update_mode(&self, message: message::i2c::UpdateMode) -> Result<()> {
+        {
+            let mut state = self.state.lock().unwrap();
+            for core in message.added_cores {
+                if !state.cores.insert(core) {
+                    bail!("Core {core} was added twice");
+                }
+            }
+            for core in message.removed_cores {
+                if !state.cores.remove(&core) {
+                    bail!("Core {core} was removed, but it was never added");
+                }
+            }
+            state.designated_ram = message.designated_ram;
+        }
+        self.conductor.schedule();
         Ok(())
     }
 
@@ -34,10 +163,16 @@ impl Invoker {
         &self,
         message: message::i2c::NotifyCompilationStatus,
     ) -> Result<()> {
+        self.conductor
+            .scheduler
+            .handle_compilation_status(self, message)?;
+        self.conductor.schedule();
         Ok(())
     }
 
     async fn notify_test_status(&self, message: message::i2c::NotifyTestStatus) -> Result<()> {
+        self.conductor.scheduler.handle_test_status(self, message)?;
+        self.conductor.schedule();
         Ok(())
     }
 
@@ -45,10 +180,39 @@ impl Invoker {
         &self,
         message: message::i2c::NotifySubmissionError,
     ) -> Result<()> {
+        self.conductor
+            .scheduler
+            .handle_submission_error(self, message)?;
+        self.conductor.schedule();
         Ok(())
     }
 
     async fn request_file(&self, message: message::i2c::RequestFile) -> Result<()> {
-        Ok(())
+        let contents = self
+            .conductor
+            .archive_store
+            .load_blob(&message.hash)
+            .await
+            .map_err(|e| errors::ConductorFailure(format!("Failed to supply file: {e:#}")));
+        if let Err(ref e) = contents {
+            println!("Invoker {} requested an unavailable file: {e}", self.name);
+        }
+        let contents = match contents {
+            Ok(contents) if self.has_capability("deflate-supply-file") => {
+                let mut encoder =
+                    flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(&contents)
+                    .and_then(|_| encoder.finish())
+                    .map_err(|e| errors::ConductorFailure(format!("Failed to compress file: {e}")))
+            }
+            contents => contents,
+        };
+        self.send_to_invoker(message::c2i::Message::SupplyFile(
+            message::c2i::SupplyFile {
+                request_id: message.request_id,
+                contents,
+            },
+        ))
     }
 }
@@ -0,0 +1,386 @@
+use crate::{archive_store, errors, submission, verdict};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tokio::sync::{mpsc, oneshot};
+
+// Append-only log of lifecycle events of the submissions in progress. Every record is a
+// little-endian u32 length followed by the MessagePack-encoded record. Records are written and
+// synced by a dedicated thread, which syncs whatever has accumulated at once, so that callers never
+// wait for the disk while holding locks.
+//
+// The history of a finalized submission is moved to a checkpoint file of its own. Checkpointed
+// submissions are dropped from the journal when it is opened, so the journal only holds the
+// submissions that were in progress.
+pub struct Journal {
+    checkpoints: PathBuf,
+    sender: mpsc::UnboundedSender<Command>,
+}
+
+#[derive(Deserialize, Serialize)]
+pub enum Record {
+    Added {
+        submission_id: String,
+        problem_id: String,
+        revision_id: String,
+        language: String,
+        files: HashMap<String, Vec<u8>>,
+        #[serde(default)]
+        rejudge_of: Option<String>,
+        #[serde(default)]
+        priority: submission::Priority,
+        #[serde(default)]
+        owner: submission::Owner,
+    },
+    Compiled {
+        submission_id: String,
+        result: Result<String, errors::Error>,
+    },
+    TestJudged {
+        submission_id: String,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    },
+    Finalized {
+        submission_id: String,
+        verdict: verdict::SubmissionVerdict,
+    },
+}
+
+// The location of the history of a finalized submission
+pub struct Checkpoint {
+    pub submission_id: String,
+    pub problem_id: String,
+    pub is_rejudge: bool,
+    pub path: PathBuf,
+}
+
+// Resolves once the record is synced to disk
+pub struct Durable(oneshot::Receiver<Result<(), String>>);
+
+enum Command {
+    Append {
+        data: Vec<u8>,
+        done: oneshot::Sender<Result<(), String>>,
+    },
+    Checkpoint {
+        path: PathBuf,
+        data: Vec<u8>,
+    },
+}
+
+impl Record {
+    pub fn get_submission_id(&self) -> &str {
+        match self {
+            Record::Added { submission_id, .. }
+            | Record::Compiled { submission_id, .. }
+            | Record::TestJudged { submission_id, .. }
+            | Record::Finalized { submission_id, .. } => submission_id,
+        }
+    }
+}
+
+impl Durable {
+    pub async fn wait(self) -> Result<()> {
+        match self.0.await {
+            Ok(result) => result.map_err(|e| anyhow!(e)),
+            Err(_) => bail!("The journal writer has stopped"),
+        }
+    }
+}
+
+impl Journal {
+    // Opens the journal, creating it if necessary, and returns the records of the submissions that
+    // were not finalized. Finalized submissions are checkpointed first. A record that was only
+    // partially written because of a crash is discarded, and the journal is truncated before it.
+    pub fn open(path: &Path, checkpoints: &Path) -> Result<(Self, Vec<Record>)> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("Failed to create directory {parent:?}"))?;
+        }
+
+        let data = match std::fs::read(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {path:?}"))?,
+        };
+        let (records, length) =
+            decode_records(&data).with_context(|| format!("Journal {path:?} is corrupted"))?;
+        if length < data.len() {
+            println!(
+                "Discarding {} bytes of an incomplete record at the end of journal {path:?}",
+                data.len() - length
+            );
+        }
+
+        let finalized: HashSet<String> = records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Finalized { submission_id, .. } => Some(submission_id.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut histories: HashMap<String, Vec<Record>> = HashMap::new();
+        let mut remaining = Vec::new();
+        for record in records {
+            if finalized.contains(record.get_submission_id()) {
+                histories
+                    .entry(record.get_submission_id().to_string())
+                    .or_default()
+                    .push(record);
+            } else {
+                remaining.push(record);
+            }
+        }
+
+        // Checkpoints are written before the journal is rewritten, so that a crash in between
+        // loses nothing
+        for records in histories.into_values() {
+            let checkpoint = get_checkpoint(checkpoints, &records)?;
+            archive_store::write_atomically(&checkpoint.path, &encode_records(&records)?)?;
+        }
+        if !finalized.is_empty() || length < data.len() {
+            println!(
+                "Moved {} finalized submissions from journal {path:?} to checkpoints",
+                finalized.len()
+            );
+            archive_store::write_atomically(path, &encode_records(&remaining)?)?;
+        }
+
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Failed to open journal {path:?}"))?;
+        file.seek(SeekFrom::End(0))
+            .with_context(|| format!("Failed to seek in {path:?}"))?;
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let path = path.to_path_buf();
+        std::thread::spawn(move || run_writer(path, file, receiver));
+
+        Ok((
+            Self {
+                checkpoints: checkpoints.to_path_buf(),
+                sender,
+            },
+            remaining,
+        ))
+    }
+
+    // Queues the record to be written. Errors are logged by the writer, and reported through the
+    // returned handle to those who wait for it.
+    pub fn append(&self, record: &Record) -> Durable {
+        let (done, receiver) = oneshot::channel();
+        match encode_records(std::slice::from_ref(record)) {
+            Ok(data) => {
+                let _ = self.sender.send(Command::Append { data, done });
+            }
+            Err(e) => {
+                println!("Failed to write to the journal: {e:?}");
+                let _ = done.send(Err(format!("{e:#}")));
+            }
+        }
+        Durable(receiver)
+    }
+
+    // Resolves once everything appended so far is synced to disk
+    pub fn flush(&self) -> Durable {
+        let (done, receiver) = oneshot::channel();
+        let _ = self.sender.send(Command::Append {
+            data: Vec::new(),
+            done,
+        });
+        Durable(receiver)
+    }
+
+    // Queues the history of a finalized submission to be written to its checkpoint. It is written
+    // after the records appended before it.
+    pub fn checkpoint(&self, records: &[Record]) -> Result<Checkpoint> {
+        let checkpoint = get_checkpoint(&self.checkpoints, records)?;
+        let data = encode_records(records)?;
+        let _ = self.sender.send(Command::Checkpoint {
+            path: checkpoint.path.clone(),
+            data,
+        });
+        Ok(checkpoint)
+    }
+
+    pub fn list_checkpoints(&self) -> Result<Vec<Checkpoint>> {
+        let mut checkpoints = Vec::new();
+        for (kind, is_rejudge) in [("submissions", false), ("rejudges", true)] {
+            let root = self.checkpoints.join(kind);
+            if !root.exists() {
+                continue;
+            }
+            for problem_dir in read_dir(&root)? {
+                let problem_id = get_file_name(&problem_dir)?;
+                for path in read_dir(&problem_dir)? {
+                    // Leftovers of writes that were interrupted are ignored
+                    let file_name = get_file_name(&path)?;
+                    let Some(submission_id) = file_name.strip_suffix(".msgpack") else {
+                        continue;
+                    };
+                    checkpoints.push(Checkpoint {
+                        submission_id: submission_id.to_string(),
+                        problem_id: problem_id.clone(),
+                        is_rejudge,
+                        path,
+                    });
+                }
+            }
+        }
+        Ok(checkpoints)
+    }
+}
+
+pub fn load_checkpoint(path: &Path) -> Result<Vec<Record>> {
+    let data = std::fs::read(path).with_context(|| format!("Failed to read {path:?}"))?;
+    let (records, length) =
+        decode_records(&data).with_context(|| format!("Checkpoint {path:?} is corrupted"))?;
+    if length < data.len() {
+        bail!("Checkpoint {path:?} is truncated");
+    }
+    Ok(records)
+}
+
+fn get_checkpoint(root: &Path, records: &[Record]) -> Result<Checkpoint> {
+    let Some(Record::Added {
+        submission_id,
+        problem_id,
+        rejudge_of,
+        ..
+    }) = records.first()
+    else {
+        bail!("The history of a submission does not start with its addition");
+    };
+    let kind = if rejudge_of.is_some() {
+        "rejudges"
+    } else {
+        "submissions"
+    };
+    Ok(Checkpoint {
+        submission_id: submission_id.clone(),
+        problem_id: problem_id.clone(),
+        is_rejudge: rejudge_of.is_some(),
+        path: root
+            .join(kind)
+            .join(problem_id)
+            .join(format!("{submission_id}.msgpack")),
+    })
+}
+
+fn run_writer(
+    path: PathBuf,
+    mut file: std::fs::File,
+    mut receiver: mpsc::UnboundedReceiver<Command>,
+) {
+    while let Some(command) = receiver.blocking_recv() {
+        let mut commands = vec![command];
+        while let Ok(command) = receiver.try_recv() {
+            commands.push(command);
+        }
+
+        let mut data = Vec::new();
+        let mut waiters = Vec::new();
+        let mut checkpoints = Vec::new();
+        for command in commands {
+            match command {
+                Command::Append { data: record, done } => {
+                    data.extend_from_slice(&record);
+                    waiters.push(done);
+                }
+                Command::Checkpoint { path, data } => checkpoints.push((path, data)),
+            }
+        }
+
+        if !waiters.is_empty() {
+            let result = append_synced(&mut file, &path, &data);
+            if let Err(ref e) = result {
+                println!("Failed to write to the journal: {e:?}");
+            }
+            for done in waiters {
+                let _ = done.send(result.as_ref().map(|_| ()).map_err(|e| format!("{e:#}")));
+            }
+        }
+
+        for (path, data) in checkpoints {
+            if let Err(e) = archive_store::write_atomically(&path, &data) {
+                println!("Failed to write checkpoint: {e:?}");
+            }
+        }
+    }
+}
+
+fn append_synced(file: &mut std::fs::File, path: &Path, data: &[u8]) -> Result<()> {
+    let position = file
+        .stream_position()
+        .with_context(|| format!("Failed to get position in {path:?}"))?;
+    let result: Result<()> = try {
+        file.write_all(data)
+            .with_context(|| format!("Failed to write to {path:?}"))?;
+        file.sync_data()
+            .with_context(|| format!("Failed to sync {path:?}"))?;
+    };
+    if result.is_err() {
+        // Do not leave a partial record in the middle of the journal
+        let _ = file.set_len(position);
+        let _ = file.seek(SeekFrom::Start(position));
+    }
+    result
+}
+
+fn encode_records(records: &[Record]) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    for record in records {
+        let record =
+            rmp_serde::to_vec_named(record).context("Failed to serialize journal record")?;
+        data.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        data.extend_from_slice(&record);
+    }
+    Ok(data)
+}
+
+// Returns the records and the length of the data they occupy, which is less than the length of the
+// data if the last record is incomplete. A crash in the middle of an append may also leave garbage
+// or zeros in place of the last record, so a record that cannot be parsed is only an error if
+// something other than zeros follows it.
+fn decode_records(data: &[u8]) -> Result<(Vec<Record>, usize)> {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some(length) = data.get(offset..offset + 4) {
+        let length = u32::from_le_bytes(length.try_into().unwrap()) as usize;
+        let Some(record) = data.get(offset + 4..offset + 4 + length) else {
+            break;
+        };
+        match rmp_serde::from_slice(record) {
+            Ok(record) => records.push(record),
+            Err(_) if data[offset + 4 + length..].iter().all(|&byte| byte == 0) => break,
+            Err(e) => {
+                Err(e).with_context(|| format!("Failed to parse record at offset {offset}"))?
+            }
+        }
+        offset += 4 + length;
+    }
+    Ok((records, offset))
+}
+
+fn read_dir(path: &Path) -> Result<Vec<PathBuf>> {
+    std::fs::read_dir(path)
+        .with_context(|| format!("Failed to read directory {path:?}"))?
+        .map(|entry| {
+            Ok(entry
+                .with_context(|| format!("Failed to read directory {path:?}"))?
+                .path())
+        })
+        .collect()
+}
+
+fn get_file_name(path: &Path) -> Result<String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+        .with_context(|| format!("Invalid file name {path:?}"))
+}
@@ -2,23 +2,35 @@
 
 mod archive_store;
 
+mod auth;
+
 mod conductor;
 
 mod config;
 
 mod errors;
 
+mod frontend;
+
 mod init;
 
 mod invoker;
 
+mod journal;
+
 mod message {
+    // Bumped on every incompatible change to c2i or i2c
+    pub(crate) const PROTOCOL_VERSION: u64 = 1;
+
+    pub(crate) mod c2f;
     pub(crate) mod c2i;
+    pub(crate) mod f2c;
     pub(crate) mod i2c;
 }
 
 mod polygon {
     pub(crate) mod converter;
+    pub(crate) mod package;
     pub(crate) mod parser;
     pub(crate) mod tests;
 }
@@ -26,10 +38,17 @@ mod polygon {
 mod problem {
     pub(crate) mod config;
     pub(crate) mod program;
+    pub(crate) mod registry;
     pub(crate) mod strategy;
     pub(crate) mod strategy_format;
 }
 
+mod scheduler;
+
+mod submission;
+
+mod tls;
+
 mod verdict;
 
 use anyhow::Result;
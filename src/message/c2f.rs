@@ -0,0 +1,81 @@
+use crate::{errors, verdict};
+use serde::Serialize;
+
+#[derive(Debug, Serialize)]
+pub enum Message {
+    Submitted(Submitted),
+    Subscribed(Subscribed),
+    SubmissionEvent(SubmissionEvent),
+    Acknowledged(Acknowledged),
+    InvokerList(InvokerList),
+    Rejudged(Rejudged),
+}
+
+#[derive(Debug, Serialize)]
+pub struct Submitted {
+    pub request_id: u64,
+    pub result: Result<String, errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Subscribed {
+    pub request_id: u64,
+    pub result: Result<(), errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Acknowledged {
+    pub request_id: u64,
+    pub result: Result<(), errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvokerList {
+    pub request_id: u64,
+    pub invokers: Vec<InvokerInfo>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InvokerInfo {
+    pub name: String,
+    pub labels: Vec<String>,
+    pub cores: Vec<u64>,
+    pub busy_cores: usize,
+    pub designated_ram: u64,
+    pub committed_ram: u64,
+    pub draining: bool,
+    pub quarantined: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rejudged {
+    pub request_id: u64,
+    // The rejudges that were queued, even if the request failed midway
+    pub submissions: Vec<RejudgedSubmission>,
+    pub result: Result<(), errors::Error>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RejudgedSubmission {
+    pub original_id: String,
+    pub submission_id: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubmissionEvent {
+    pub submission_id: String,
+    pub event: Event,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub enum Event {
+    CompilationStatus(Result<String, errors::Error>),
+    TestStatus(TestStatus),
+    Finalized(verdict::SubmissionVerdict),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TestStatus {
+    pub test: u64,
+    pub judgement_result: verdict::TestJudgementResult,
+}
@@ -1,9 +1,11 @@
-use crate::verdict::InvocationLimit;
+use crate::{errors, verdict::InvocationLimit};
 use serde::Serialize;
 use std::collections::HashMap;
 
 #[derive(Debug, Serialize)]
 pub enum Message {
+    Challenge(Challenge),
+    HandshakeResult(HandshakeResult),
     AddSubmission(AddSubmission),
     PushToJudgementQueue(PushToJudgementQueue),
     CancelJudgementOnTests(CancelJudgementOnTests),
@@ -11,6 +13,23 @@ pub enum Message {
     SupplyFile(SupplyFile),
 }
 
+#[derive(Debug, Serialize)]
+pub struct Challenge {
+    pub nonce: Vec<u8>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct HandshakeResult {
+    // On failure, the reason why the invoker was rejected
+    pub result: Result<NegotiatedFeatures, String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct NegotiatedFeatures {
+    // Optional features that both sides support and that are going to be used
+    pub capabilities: Vec<String>,
+}
+
 #[derive(Debug, Serialize)]
 pub struct AddSubmission {
     pub compilation_core: u64,
@@ -43,5 +62,5 @@ pub struct FinalizeSubmission {
 #[derive(Debug, Serialize)]
 pub struct SupplyFile {
     pub request_id: u64,
-    pub contents: Vec<u8>,
+    pub contents: Result<Vec<u8>, errors::Error>,
 }
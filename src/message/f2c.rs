@@ -0,0 +1,65 @@
+use crate::submission;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Deserialize)]
+pub enum Message {
+    Submit(Submit),
+    Subscribe(Subscribe),
+    SetInvokerDraining(SetInvokerDraining),
+    ListInvokers(ListInvokers),
+    Rejudge(Rejudge),
+    LiftInvokerQuarantine(LiftInvokerQuarantine),
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Submit {
+    pub request_id: u64,
+    pub problem_id: String,
+    pub language: String,
+    pub files: HashMap<String, Vec<u8>>,
+    #[serde(default)]
+    pub priority: submission::Priority,
+    #[serde(default)]
+    pub owner: submission::Owner,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Subscribe {
+    pub request_id: u64,
+    pub submission_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetInvokerDraining {
+    pub request_id: u64,
+    pub invoker_name: String,
+    pub draining: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ListInvokers {
+    pub request_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct LiftInvokerQuarantine {
+    pub request_id: u64,
+    pub invoker_name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Rejudge {
+    pub request_id: u64,
+    pub target: RejudgeTarget,
+    // The latest revision of the problem if absent
+    pub revision_id: Option<String>,
+    // All tests if absent
+    pub tests: Option<Vec<u64>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub enum RejudgeTarget {
+    Submissions(Vec<String>),
+    Problem(String),
+}
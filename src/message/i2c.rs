@@ -4,6 +4,7 @@ use serde::Deserialize;
 #[derive(Debug, Deserialize)]
 pub enum Message {
     Handshake(Handshake),
+    ChallengeResponse(ChallengeResponse),
     UpdateMode(UpdateMode),
     NotifyCompilationStatus(NotifyCompilationStatus),
     NotifyTestStatus(NotifyTestStatus),
@@ -14,6 +15,22 @@ pub enum Message {
 #[derive(Debug, Deserialize)]
 pub struct Handshake {
     pub invoker_name: String,
+    // Invokers that predate versioning send neither field, and are rejected as version 0
+    #[serde(default)]
+    pub protocol_version: u64,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    // Arbitrary tags, e.g. "fast-cpu", that problems and languages may require
+    #[serde(default)]
+    pub labels: Vec<String>,
+    // Languages the invoker has toolchains for. An invoker that sends none supports all languages.
+    #[serde(default)]
+    pub languages: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ChallengeResponse {
+    pub mac: Vec<u8>,
 }
 
 #[derive(Debug, Deserialize)]
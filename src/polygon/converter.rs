@@ -2,6 +2,7 @@ use crate::{
     archive_store,
     polygon::{parser, tests},
     problem::{config, program, strategy, strategy_format},
+    verdict,
 };
 use anyhow::{bail, Context, Result};
 use std::collections::HashMap;
@@ -101,7 +102,7 @@ block check
 pub fn create_archive_from_polygon(
     polygon_file_reader: &impl Fn(&Path) -> Result<Vec<u8>>,
     archive_store: &archive_store::ArchiveStore,
-) -> Result<()> {
+) -> Result<(config::ProblemRevision, archive_store::Archive)> {
     let problem_xml =
         polygon_file_reader(&Path::new("problem.xml")).context("Failed to read problem.xml")?;
     let problem_xml =
@@ -205,23 +206,54 @@ pub fn create_archive_from_polygon(
 
     let dependency_graph = tests::generate_dependency_graph(&problem_xml.judging)?;
 
+    // Limits are per-submission, not per-test, so all testsets have to agree on them
+    let first_testset = problem_xml
+        .judging
+        .testset
+        .first()
+        .context("problem.xml contains no testsets")?;
+    for testset in &problem_xml.judging.testset {
+        if testset.time_limit != first_testset.time_limit
+            || testset.memory_limit != first_testset.memory_limit
+        {
+            bail!(
+                "Testsets {} and {} have different limits, which is not supported",
+                first_testset.name,
+                testset.name
+            );
+        }
+    }
+    let invocation_limit = verdict::InvocationLimit {
+        real_time: std::time::Duration::from_millis(first_testset.time_limit * 2),
+        cpu_time: std::time::Duration::from_millis(first_testset.time_limit),
+        memory: first_testset.memory_limit as usize,
+    };
+    let invocation_limits = parsed_strategy
+        .blocks
+        .iter()
+        .filter(|block| matches!(block.tactic, strategy::Tactic::User))
+        .map(|block| (block.name.clone(), invocation_limit.clone()))
+        .collect();
+
     let mut archive = archive_store::Archive::new();
 
     let mut programs = HashMap::new();
     add_program(
+        polygon_file_reader,
+        archive_store,
         &mut archive,
         &mut programs,
         "checker".to_string(),
         &problem_xml.assets.checker.source,
-        &problem_xml.assets.checker.binary,
     )?;
     if let Some(ref interactor) = problem_xml.assets.interactor {
         add_program(
+            polygon_file_reader,
+            archive_store,
             &mut archive,
             &mut programs,
             "interactor".to_string(),
             &interactor.source,
-            &interactor.binary,
         )?;
     }
 
@@ -230,7 +262,7 @@ pub fn create_archive_from_polygon(
         archive_store,
         &problem_xml,
         &mut archive,
-    );
+    )?;
 
     let problem = config::ProblemRevision {
         dependency_graph,
@@ -240,25 +272,45 @@ pub fn create_archive_from_polygon(
             programs,
             root: PathBuf::new(),
         },
+        invocation_limits,
     };
 
-    Ok(())
+    Ok((problem, archive))
 }
 
+// Polygon binaries are built for Windows, so the program is stored as source and compiled by the
+// invoker
 fn add_program(
+    polygon_file_reader: &impl Fn(&Path) -> Result<Vec<u8>>,
+    archive_store: &archive_store::ArchiveStore,
     archive: &mut archive_store::Archive,
     programs: &mut HashMap<String, program::CachedProgram>,
     name: String,
     source: &parser::Source,
-    binary: &parser::Binary,
 ) -> Result<()> {
-    let binary_name = &binary.path.rsplit_once('/').unzip().1.unwrap_or(&binary.path);
+    let source_name = &source
+        .path
+        .rsplit_once('/')
+        .unzip()
+        .1
+        .unwrap_or(&source.path);
 
-    programs.insert(name, program::CachedProgram {
-        pub package: String,
-        pub prerequisites: Vec<String>,
-        argv: vec![binary_name]
-    });
+    let data = polygon_file_reader(Path::new(&source.path))
+        .with_context(|| format!("Failed to read {name} source from {}", source.path))?;
+    let handle = archive_store
+        .store_blob(data)
+        .context("Internal storage error")?;
+    let archive_path = format!("programs/{name}/{source_name}");
+    archive.add_file(archive_path.clone(), handle, false);
+
+    programs.insert(
+        name,
+        program::CachedProgram {
+            package: source.type_.clone(),
+            prerequisites: vec![archive_path],
+            argv: vec![source_name.to_string()],
+        },
+    );
 
     Ok(())
 }
@@ -0,0 +1,132 @@
+use anyhow::{bail, Context, Result};
+use std::cell::RefCell;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+// Test files of large problems can be quite big, but anything above this is most likely a zip bomb
+const MAX_FILE_SIZE: u64 = 1 << 30;
+
+pub fn is_safe_relative_path(path: &str) -> bool {
+    !(
+        // Access to parent directory may lead to sandbox escape
+        path == ".."
+            || path.contains("/../")
+            || path.starts_with("../")
+            || path.ends_with("/..")
+            // Absolute path
+            || path.starts_with("/")
+            // OS-dependent path separator
+            || path.contains('\\')
+            // Absolute path (C:/...) or NTFS alternate stream
+            || path.contains(':')
+    )
+}
+
+fn check_path(path: &Path) -> Result<&str> {
+    let path = path
+        .to_str()
+        .with_context(|| format!("Path {path:?} is not valid UTF-8"))?;
+    if path.is_empty() || !is_safe_relative_path(path) {
+        bail!(
+            "Path {path:?} is invalid: it must be a non-empty relative path, and not contain \
+             /../, \\, or :"
+        );
+    }
+    Ok(path)
+}
+
+pub struct DirectoryPackage {
+    root: PathBuf,
+}
+
+impl DirectoryPackage {
+    pub fn open(root: &Path) -> Result<Self> {
+        if !root.is_dir() {
+            bail!("{root:?} is not a directory");
+        }
+        Ok(Self {
+            root: root.to_path_buf(),
+        })
+    }
+
+    pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let path = check_path(path)?;
+
+        // Check every component, so that a symlinked directory cannot be used to escape the
+        // package either
+        let mut full_path = self.root.clone();
+        for component in path.split('/') {
+            full_path.push(component);
+            let metadata = std::fs::symlink_metadata(&full_path)
+                .with_context(|| format!("File {path:?} is not present in the package"))?;
+            if metadata.file_type().is_symlink() {
+                bail!("{full_path:?} is a symlink, which is not allowed in packages");
+            }
+        }
+
+        let metadata = std::fs::metadata(&full_path)
+            .with_context(|| format!("Failed to stat {full_path:?}"))?;
+        if !metadata.is_file() {
+            bail!("{path:?} is not a regular file");
+        }
+        if metadata.len() > MAX_FILE_SIZE {
+            bail!(
+                "{path:?} is {} bytes long, which exceeds the limit of {MAX_FILE_SIZE} bytes",
+                metadata.len()
+            );
+        }
+
+        std::fs::read(&full_path).with_context(|| format!("Failed to read {full_path:?}"))
+    }
+}
+
+pub struct ZipPackage {
+    archive: RefCell<zip::ZipArchive<std::fs::File>>,
+}
+
+impl ZipPackage {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path:?}"))?;
+        let archive = zip::ZipArchive::new(file)
+            .with_context(|| format!("{path:?} is not a valid zip archive"))?;
+        Ok(Self {
+            archive: RefCell::new(archive),
+        })
+    }
+
+    pub fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let path = check_path(path)?;
+
+        let mut archive = self.archive.borrow_mut();
+        let mut file = archive
+            .by_name(path)
+            .with_context(|| format!("File {path:?} is not present in the package"))?;
+
+        if file.is_dir() {
+            bail!("{path:?} is a directory, not a file");
+        }
+        if let Some(mode) = file.unix_mode() {
+            if mode & 0o170000 == 0o120000 {
+                bail!("{path:?} is a symlink, which is not allowed in packages");
+            }
+        }
+        if file.size() > MAX_FILE_SIZE {
+            bail!(
+                "{path:?} is {} bytes long, which exceeds the limit of {MAX_FILE_SIZE} bytes",
+                file.size()
+            );
+        }
+
+        // The size in the header may lie, so limit the amount of decompressed data as well
+        let mut data = Vec::with_capacity(file.size() as usize);
+        (&mut file)
+            .take(MAX_FILE_SIZE + 1)
+            .read_to_end(&mut data)
+            .with_context(|| format!("Failed to decompress {path:?}"))?;
+        if data.len() as u64 > MAX_FILE_SIZE {
+            bail!("{path:?} exceeds the limit of {MAX_FILE_SIZE} bytes after decompression");
+        }
+
+        Ok(data)
+    }
+}
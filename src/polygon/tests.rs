@@ -1,4 +1,8 @@
-use crate::{archive_store, polygon::parser, problem::config};
+use crate::{
+    archive_store,
+    polygon::{package, parser},
+    problem::config,
+};
 use anyhow::{bail, Context, Result};
 use std::collections::HashMap;
 use std::path::Path;
@@ -11,18 +15,7 @@ struct FileNamePattern<'a> {
 
 impl<'a> FileNamePattern<'a> {
     fn from_printf_format(pattern: &'a str) -> Result<Self> {
-        if
-        // Access to parent directory may lead to sandbox escape
-        pattern.contains("/../")
-            || pattern.starts_with("../")
-            || pattern.ends_with("/..")
-            // Absolute path
-            || pattern.starts_with("/")
-            // OS-dependent path separator
-            || pattern.contains('\\')
-            // Absolute path (C:/...) or NTFS alternate stream
-            || pattern.contains(':')
-        {
+        if !package::is_safe_relative_path(pattern) {
             bail!(
                 "Format string {pattern:?} is invalid: it must be a relative path, and not \
                  contain /../, \\, or :"
@@ -93,7 +86,7 @@ pub fn generate_dependency_graph(judging: &parser::Judging) -> Result<config::De
     // are pretests and system tests, but that is not a problem because we have deduplication.
     let mut dependents_of: Vec<Vec<u64>> = Vec::new();
 
-    for testset in judging.testset {
+    for testset in &judging.testset {
         // Sanity checks
         if testset.test_count != testset.tests.len() {
             bail!(
@@ -114,7 +107,7 @@ pub fn generate_dependency_graph(judging: &parser::Judging) -> Result<config::De
         let mut tests_by_group = HashMap::new();
         for (test_id, test) in testset.tests.iter().enumerate() {
             tests_by_group
-                .entry(test.group)
+                .entry(test.group.clone())
                 .or_insert_with(Vec::new)
                 .push(test_id);
         }
@@ -202,7 +195,7 @@ pub fn generate_dependency_graph(judging: &parser::Judging) -> Result<config::De
 
             // Handle group dependencies
             if let Some(ref dependencies) = group.dependencies {
-                for dependency in dependencies.dependency {
+                for dependency in &dependencies.dependency {
                     let test_dependencies =
                         tests_by_group.get(&dependency.group).with_context(|| {
                             format!(
@@ -213,8 +206,8 @@ pub fn generate_dependency_graph(judging: &parser::Judging) -> Result<config::De
                     // FIXME: Yes, quadratic complexity, screw me
                     for test in test_dependencies {
                         for dependent_test in tests {
-                            dependents_of[testset_offset + dependent_test]
-                                .push((testset_offset + test) as u64);
+                            dependents_of[testset_offset + test]
+                                .push((testset_offset + dependent_test) as u64);
                         }
                     }
                 }
@@ -242,7 +235,7 @@ pub fn add_tests_to_archive(
 ) -> Result<()> {
     let mut i = 0usize;
 
-    for testset in problem_xml.judging.testset {
+    for testset in &problem_xml.judging.testset {
         let mut path_patterns = HashMap::new();
 
         if let Some(ref pattern) = testset.input_path_pattern {
@@ -252,7 +245,7 @@ pub fn add_tests_to_archive(
             path_patterns.insert("answer", FileNamePattern::from_printf_format(pattern)?);
         }
 
-        for pattern in testset.path_pattern {
+        for pattern in &testset.path_pattern {
             if pattern.name.contains('/') || pattern.name.contains('\\') {
                 bail!(
                     "The name of path pattern {:?} is invalid because it contains a slash",
@@ -1,14 +1,115 @@
-use crate::problem::strategy;
-use serde::Serialize;
+use crate::{problem::strategy, verdict};
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct ProblemRevision {
     pub dependency_graph: DependencyGraph,
     pub strategy_factory: strategy::StrategyFactory,
+    // Keyed by the names of blocks that run user code
+    pub invocation_limits: HashMap<String, verdict::InvocationLimit>,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct DependencyGraph {
     pub dependents_of: HashMap<u64, Vec<u64>>,
 }
+
+// Tests that depend on each other, e.g. the ring that models a complete group, form a strongly
+// connected component. Components are judged in dependency order, while the tests of one component
+// may run simultaneously.
+pub struct Condensation {
+    pub component_of: Vec<usize>,
+    pub components: Vec<Vec<u64>>,
+    // Other components that have a test some test of the component depends on
+    pub dependencies_of: Vec<Vec<usize>>,
+}
+
+impl DependencyGraph {
+    pub fn condense(&self) -> Condensation {
+        let test_count = self.dependents_of.len();
+        let get_dependents = |test: usize| {
+            self.dependents_of
+                .get(&(test as u64))
+                .map_or(&[][..], |dependents| dependents.as_slice())
+        };
+
+        // Tarjan's algorithm without recursion, since an icpc chain may be thousands of tests long
+        let mut index = vec![usize::MAX; test_count];
+        let mut low_link = vec![0; test_count];
+        let mut on_stack = vec![false; test_count];
+        let mut stack = Vec::new();
+        let mut component_of = vec![0; test_count];
+        let mut components = Vec::new();
+        let mut next_index = 0;
+
+        for root in 0..test_count {
+            if index[root] != usize::MAX {
+                continue;
+            }
+            // (test, the position of the next dependent to visit)
+            let mut call_stack = vec![(root, 0)];
+            index[root] = next_index;
+            low_link[root] = next_index;
+            next_index += 1;
+            stack.push(root);
+            on_stack[root] = true;
+
+            while let Some(&(test, position)) = call_stack.last() {
+                if let Some(&dependent) = get_dependents(test).get(position) {
+                    call_stack.last_mut().unwrap().1 += 1;
+                    let dependent = dependent as usize;
+                    if index[dependent] == usize::MAX {
+                        index[dependent] = next_index;
+                        low_link[dependent] = next_index;
+                        next_index += 1;
+                        stack.push(dependent);
+                        on_stack[dependent] = true;
+                        call_stack.push((dependent, 0));
+                    } else if on_stack[dependent] {
+                        low_link[test] = low_link[test].min(index[dependent]);
+                    }
+                    continue;
+                }
+
+                call_stack.pop();
+                if let Some(&(parent, _)) = call_stack.last() {
+                    low_link[parent] = low_link[parent].min(low_link[test]);
+                }
+                if low_link[test] == index[test] {
+                    let mut component = Vec::new();
+                    loop {
+                        let member = stack.pop().unwrap();
+                        on_stack[member] = false;
+                        component_of[member] = components.len();
+                        component.push(member as u64);
+                        if member == test {
+                            break;
+                        }
+                    }
+                    components.push(component);
+                }
+            }
+        }
+
+        let mut dependencies_of = vec![Vec::new(); components.len()];
+        for test in 0..test_count {
+            for &dependent in get_dependents(test) {
+                let (from, to) = (component_of[test], component_of[dependent as usize]);
+                if from != to {
+                    dependencies_of[to].push(from);
+                }
+            }
+        }
+        for dependencies in &mut dependencies_of {
+            dependencies.sort_unstable();
+            dependencies.dedup();
+        }
+
+        Condensation {
+            component_of,
+            components,
+            dependencies_of,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::{archive_store, polygon::converter, problem::config};
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+// Revisions are immutable once imported: a new import of the same problem always creates a new
+// revision, so submissions that are already being judged keep using the revision they started with.
+pub struct ProblemRegistry {
+    revisions: Mutex<HashMap<(String, String), Arc<config::ProblemRevision>>>,
+}
+
+impl ProblemRegistry {
+    pub fn new() -> Self {
+        Self {
+            revisions: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Imports a Polygon package as a new revision of the problem and returns the revision id
+    pub fn import_polygon(
+        &self,
+        archive_store: &archive_store::ArchiveStore,
+        polygon_file_reader: &impl Fn(&Path) -> Result<Vec<u8>>,
+        problem_id: &str,
+    ) -> Result<String> {
+        let (revision, archive) =
+            converter::create_archive_from_polygon(polygon_file_reader, archive_store)?;
+
+        let revision_id = archive_store
+            .allocate_revision_id(problem_id)
+            .context("Failed to allocate revision id")?;
+        archive_store
+            .save_revision(problem_id, &revision_id, &revision, &archive)
+            .context("Failed to save the problem revision")?;
+
+        self.revisions.lock().unwrap().insert(
+            (problem_id.to_string(), revision_id.clone()),
+            Arc::new(revision),
+        );
+
+        Ok(revision_id)
+    }
+
+    pub fn get_revision(
+        &self,
+        archive_store: &archive_store::ArchiveStore,
+        problem_id: &str,
+        revision_id: &str,
+    ) -> Result<Arc<config::ProblemRevision>> {
+        let key = (problem_id.to_string(), revision_id.to_string());
+        if let Some(revision) = self.revisions.lock().unwrap().get(&key) {
+            return Ok(revision.clone());
+        }
+
+        // Loading from disk is done without holding the lock. Two threads may load the same
+        // revision concurrently, but as revisions are immutable, that is harmless.
+        let (revision, _) = archive_store.load_revision(problem_id, revision_id)?;
+        let revision = Arc::new(revision);
+        Ok(self
+            .revisions
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert(revision)
+            .clone())
+    }
+
+    pub fn get_latest_revision_id(
+        &self,
+        archive_store: &archive_store::ArchiveStore,
+        problem_id: &str,
+    ) -> Result<String> {
+        let revisions = archive_store.list_revisions(problem_id)?;
+        let revision_id = revisions
+            .last()
+            .with_context(|| format!("Problem {problem_id} does not exist or has no revisions"))?;
+        Ok(revision_id.to_string())
+    }
+}
@@ -1,9 +1,9 @@
 use crate::problem::program;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::path::PathBuf;
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct StrategyFactory {
     pub files: HashMap<String, FileType>,
     pub blocks: Vec<Block>,
@@ -11,7 +11,7 @@ pub struct StrategyFactory {
     pub root: PathBuf,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Block {
     pub name: String,
     pub tactic: Tactic,
@@ -23,26 +23,26 @@ pub struct Block {
     pub stderr: Option<Pattern>,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub enum Tactic {
     User,
     Testlib,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub enum FileType {
     Regular,
     Pipe,
 }
 
-#[derive(Serialize)]
+#[derive(Deserialize, Serialize)]
 pub struct Binding {
     pub readable: bool,
     pub writable: bool,
     pub source: Pattern,
 }
 
-#[derive(Clone, Serialize)]
+#[derive(Clone, Deserialize, Serialize)]
 pub enum Pattern {
     File(String),
     VariableText(String),
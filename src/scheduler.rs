@@ -0,0 +1,1123 @@
+use crate::{config, errors, invoker, journal, message, problem, submission, verdict};
+use anyhow::{bail, Context, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+pub struct Scheduler {
+    state: Mutex<SchedulerState>,
+    config: config::SchedulerConfig,
+}
+
+struct SchedulerState {
+    submissions: HashMap<String, submission::Submission>,
+    // Submission ids in the order of arrival, which is also the order in which tests are dispatched
+    queue: VecDeque<String>,
+    // (invoker name, core) pairs that currently run a test, mapped to the RAM committed to the test
+    busy_cores: HashMap<(String, u64), u64>,
+    fair_share: FairShare,
+    journal: journal::Journal,
+    // Finalized submissions whose history is checkpointed, including the ones evicted from memory
+    checkpoints: HashMap<String, journal::Checkpoint>,
+    // Set on shutdown. Submissions that have not reached an invoker stay in the journal for the
+    // next start.
+    stopped: bool,
+}
+
+// Stride scheduling over owners: every owner has a pass that advances by 1 / weight for each core it
+// is given, and the owner with the smallest pass goes next. This is weighted round-robin that
+// stays fair when owners come and go.
+struct FairShare {
+    passes: HashMap<submission::Owner, f64>,
+    // The pass of the owner that was served last. Owners that were idle start from here, so that
+    // they do not accumulate credit while they have nothing queued.
+    global_pass: f64,
+}
+
+impl FairShare {
+    fn get_pass(&self, owner: &submission::Owner) -> f64 {
+        self.passes
+            .get(owner)
+            .copied()
+            .unwrap_or(0.0)
+            .max(self.global_pass)
+    }
+
+    fn charge(&mut self, owner: submission::Owner, weight: u64) {
+        let pass = self.get_pass(&owner);
+        self.global_pass = pass;
+        self.passes.insert(owner, pass + 1.0 / weight as f64);
+        // Owners that are behind the global pass are indistinguishable from new ones
+        let global_pass = self.global_pass;
+        self.passes.retain(|_, pass| *pass > global_pass);
+    }
+}
+
+impl Scheduler {
+    pub fn new(journal: journal::Journal, config: config::SchedulerConfig) -> Self {
+        Self {
+            state: Mutex::new(SchedulerState {
+                submissions: HashMap::new(),
+                queue: VecDeque::new(),
+                busy_cores: HashMap::new(),
+                fair_share: FairShare {
+                    passes: HashMap::new(),
+                    global_pass: 0.0,
+                },
+                journal,
+                checkpoints: HashMap::new(),
+                stopped: false,
+            }),
+            config,
+        }
+    }
+
+    // Rebuilds the submissions from the journal after a restart. Submissions that were not finalized
+    // are queued again, and only their tests without a verdict are going to be run. Finalized
+    // submissions stay in their checkpoints until they are needed.
+    pub fn replay_journal(
+        &self,
+        records: Vec<journal::Record>,
+        checkpoints: Vec<journal::Checkpoint>,
+        get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        for checkpoint in checkpoints {
+            state
+                .checkpoints
+                .insert(checkpoint.submission_id.clone(), checkpoint);
+        }
+
+        for record in records {
+            let added = match record {
+                journal::Record::Added {
+                    ref submission_id, ..
+                } => Some(submission_id.clone()),
+                _ => None,
+            };
+            match apply_record(&mut state.submissions, record, &get_problem) {
+                Ok(()) => state.queue.extend(added),
+                Err(e) => println!("Failed to replay journal record: {e:?}"),
+            }
+        }
+        let submissions = &state.submissions;
+        state.queue.retain(|id| {
+            submissions
+                .get(id)
+                .map_or(false, |submission| !submission.is_finalized())
+        });
+
+        // The conductor may have stopped after the last test was judged, but before the submission
+        // was finalized
+        let mut finished = Vec::new();
+        for submission_id in &state.queue {
+            let submission = &state.submissions[submission_id];
+            if submission.is_finished() {
+                finished.push((submission_id.clone(), submission.get_tests_verdict()));
+            }
+        }
+        for (submission_id, verdict) in finished {
+            record_verdict(state, &submission_id, verdict);
+            state.queue.retain(|id| *id != submission_id);
+        }
+
+        println!(
+            "Restored {} submissions from the journal, {} of them are queued",
+            state.submissions.len(),
+            state.queue.len()
+        );
+    }
+
+    pub async fn add_submission(&self, submission: submission::Submission) -> Result<()> {
+        let durable = {
+            let state = self.state.lock().unwrap();
+            if state.submissions.contains_key(&submission.id) {
+                bail!("Submission {} is already being judged", submission.id);
+            }
+            state.journal.append(&make_added_record(&submission))
+        };
+        // The submission is only accepted once it is durably recorded
+        durable
+            .wait()
+            .await
+            .context("Failed to record the submission in the journal")?;
+
+        let mut state = self.state.lock().unwrap();
+        state.queue.push_back(submission.id.clone());
+        state.submissions.insert(submission.id.clone(), submission);
+        Ok(())
+    }
+
+    // Queues a rejudge of a finalized submission. The original submission and its results are left
+    // intact. If only some tests are rejudged, the results of the other tests are carried over from
+    // the original submission.
+    pub async fn add_rejudge(
+        &self,
+        mut submission: submission::Submission,
+        tests: Option<&[u64]>,
+        get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+    ) -> Result<()> {
+        let original_id = submission
+            .rejudge_of
+            .clone()
+            .context("The submission is not a rejudge")?;
+        let (is_finalized, owner, test_count, judged_tests) =
+            self.with_submission(&original_id, get_problem, |original| {
+                (
+                    original.is_finalized(),
+                    original.owner.clone(),
+                    original.get_test_count(),
+                    original.get_judged_tests(),
+                )
+            })?;
+        if !is_finalized {
+            bail!("Submission {original_id} is still being judged");
+        }
+        submission.owner = owner;
+
+        let mut carried_over = Vec::new();
+        if let Some(tests) = tests {
+            if test_count != submission.get_test_count() {
+                bail!(
+                    "Submission {original_id} has {test_count} tests, but the revision it is \
+                     rejudged against has {}, so tests cannot be rejudged selectively",
+                    submission.get_test_count()
+                );
+            }
+            if let Some(test) = tests
+                .iter()
+                .find(|&&test| test >= submission.get_test_count())
+            {
+                bail!("Test {test} does not exist");
+            }
+            carried_over = judged_tests
+                .into_iter()
+                .filter(|(test, _)| !tests.contains(test))
+                .collect();
+        }
+        for (test, judgement_result) in &carried_over {
+            submission.restore_judgement(*test, judgement_result.clone())?;
+        }
+        // A failure that is carried over ignores its dependents, so they would never be run
+        if let Some(test) = tests.into_iter().flatten().find(|&&test| {
+            matches!(
+                submission.get_test_state(test),
+                Ok(submission::TestState::Ignored)
+            )
+        }) {
+            bail!(
+                "Test {test} depends on a test that failed in submission {original_id}, so it \
+                 cannot be rejudged without that test"
+            );
+        }
+
+        let durables = {
+            let state = self.state.lock().unwrap();
+            if state.submissions.contains_key(&submission.id) {
+                bail!("Submission {} is already being judged", submission.id);
+            }
+            let mut durables = vec![state.journal.append(&make_added_record(&submission))];
+            for (test, judgement_result) in carried_over {
+                durables.push(state.journal.append(&journal::Record::TestJudged {
+                    submission_id: submission.id.clone(),
+                    test,
+                    judgement_result,
+                }));
+            }
+            durables
+        };
+        for durable in durables {
+            durable
+                .wait()
+                .await
+                .context("Failed to record the rejudge in the journal")?;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let submission_id = submission.id.clone();
+        let verdict = submission
+            .is_finished()
+            .then(|| submission.get_tests_verdict());
+        state.submissions.insert(submission_id.clone(), submission);
+        // Happens if no tests are left to be rejudged
+        match verdict {
+            Some(verdict) => record_verdict(state, &submission_id, verdict),
+            None => state.queue.push_back(submission_id),
+        }
+        Ok(())
+    }
+
+    // The problem, language and files of a submission, as needed to rejudge it
+    pub fn get_submission_source(
+        &self,
+        submission_id: &str,
+        get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+    ) -> Result<(String, String, HashMap<String, Vec<u8>>)> {
+        self.with_submission(submission_id, get_problem, |submission| {
+            (
+                submission.problem_id.clone(),
+                submission.language.clone(),
+                submission.files.clone(),
+            )
+        })
+    }
+
+    // Ids of the finalized submissions of the problem, excluding rejudges, in increasing order.
+    // Submissions that are still being judged cannot be rejudged yet, so they are skipped.
+    pub fn get_submissions_of_problem(&self, problem_id: &str) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut submission_ids: Vec<&String> = state
+            .submissions
+            .values()
+            .filter(|submission| {
+                submission.problem_id == problem_id
+                    && submission.rejudge_of.is_none()
+                    && submission.is_finalized()
+            })
+            .map(|submission| &submission.id)
+            .chain(
+                state
+                    .checkpoints
+                    .values()
+                    .filter(|checkpoint| {
+                        checkpoint.problem_id == problem_id && !checkpoint.is_rejudge
+                    })
+                    .map(|checkpoint| &checkpoint.submission_id),
+            )
+            .collect();
+        submission_ids.sort_by_key(|id| (id.len(), *id));
+        submission_ids.dedup();
+        submission_ids.into_iter().cloned().collect()
+    }
+
+    // Runs f on the submission, loading it from its checkpoint if it was evicted from memory. The
+    // file is read without holding the lock.
+    fn with_submission<T>(
+        &self,
+        submission_id: &str,
+        get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+        f: impl FnOnce(&mut submission::Submission) -> T,
+    ) -> Result<T> {
+        let path = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(submission) = state.submissions.get_mut(submission_id) {
+                return Ok(f(submission));
+            }
+            state
+                .checkpoints
+                .get(submission_id)
+                .with_context(|| format!("Unknown submission {submission_id}"))?
+                .path
+                .clone()
+        };
+        let mut submissions = HashMap::new();
+        for record in journal::load_checkpoint(&path)? {
+            apply_record(&mut submissions, record, &get_problem)?;
+        }
+        let mut submission = submissions
+            .remove(submission_id)
+            .with_context(|| format!("Checkpoint {path:?} has no submission {submission_id}"))?;
+        Ok(f(&mut submission))
+    }
+
+    // Pushes runnable tests to free cores until either runs out
+    pub fn schedule(&self, invokers: &[Arc<invoker::Invoker>]) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+
+        let submission_ids = self.get_submissions_by_priority(state);
+        let invoker_states: Vec<invoker::InvokerState> =
+            invokers.iter().map(|invoker| invoker.get_state()).collect();
+
+        'invoker: for (invoker, invoker_state) in invokers.iter().zip(&invoker_states) {
+            let mut free_cores: Vec<u64> = invoker_state
+                .cores
+                .iter()
+                .copied()
+                .filter(|&core| {
+                    !state
+                        .busy_cores
+                        .contains_key(&(invoker.get_name().to_string(), core))
+                })
+                .collect();
+            free_cores.sort_unstable();
+
+            let mut available_ram = invoker_state
+                .designated_ram
+                .saturating_sub(get_committed_ram(state, invoker.get_name()));
+            let mut compilations = state
+                .submissions
+                .values()
+                .filter(|submission| {
+                    matches!(
+                        submission.get_compilation_state(),
+                        submission::CompilationState::InProgress {
+                            invoker: ref invoker_name,
+                            ..
+                        } if invoker_name == invoker.get_name()
+                    )
+                })
+                .count() as u64;
+
+            for core in free_cores {
+                let Some(submission_id) = pick_submission(
+                    state,
+                    &self.config,
+                    &submission_ids,
+                    invokers,
+                    &invoker_states,
+                    invoker,
+                    invoker_state,
+                    available_ram,
+                    compilations < self.config.max_compilations_per_invoker,
+                ) else {
+                    continue 'invoker;
+                };
+                let submission = state.submissions.get_mut(&submission_id).unwrap();
+
+                let mut committed_ram = 0;
+                if let submission::CompilationState::Pending = submission.get_compilation_state() {
+                    let result = invoker.send_to_invoker(message::c2i::Message::AddSubmission(
+                        submission.make_add_submission(core),
+                    ));
+                    if let Err(e) = result {
+                        println!("Failed to add submission: {e:?}");
+                        continue 'invoker;
+                    }
+                    submission.mark_compiling(invoker.get_name().to_string(), core);
+                    compilations += 1;
+                } else {
+                    let test = submission.next_runnable_test().unwrap();
+                    let result =
+                        invoker.send_to_invoker(message::c2i::Message::PushToJudgementQueue(
+                            message::c2i::PushToJudgementQueue {
+                                core,
+                                submission_id: submission_id.clone(),
+                                tests: vec![test],
+                            },
+                        ));
+                    if let Err(e) = result {
+                        println!("Failed to push test to the judgement queue: {e:?}");
+                        continue 'invoker;
+                    }
+                    submission.mark_queued(test, core);
+                    committed_ram = submission.get_test_ram();
+                    available_ram -= committed_ram;
+                }
+
+                let owner = submission.owner.clone();
+                let weight = self.get_owner_weight(&owner);
+                state.fair_share.charge(owner, weight);
+                state
+                    .busy_cores
+                    .insert((invoker.get_name().to_string(), core), committed_ram);
+            }
+        }
+    }
+
+    fn get_owner_weight(&self, owner: &submission::Owner) -> u64 {
+        let get_weight = |weights: &HashMap<String, u64>, id: &Option<String>| {
+            id.as_ref()
+                .and_then(|id| weights.get(id))
+                .copied()
+                .unwrap_or(1)
+        };
+        (get_weight(&self.config.contest_weights, &owner.contest_id)
+            * get_weight(&self.config.user_weights, &owner.user_id))
+        .max(1)
+    }
+
+    // Orders queued submissions by priority class, and by arrival within a class. Every
+    // aging_interval_ms a submission waits, it is promoted by one class, so that lower classes are
+    // never starved by a steady stream of higher-priority submissions.
+    fn get_submissions_by_priority(&self, state: &SchedulerState) -> Vec<(u64, String)> {
+        let now = Instant::now();
+        let aging_interval = Duration::from_millis(self.config.aging_interval_ms.max(1));
+        let mut submission_ids: Vec<(u64, usize, &String)> = state
+            .queue
+            .iter()
+            .enumerate()
+            .map(|(position, submission_id)| {
+                let submission = &state.submissions[submission_id];
+                let promotions = (now
+                    .saturating_duration_since(submission.queued_at)
+                    .as_millis()
+                    / aging_interval.as_millis()) as u64;
+                (
+                    (submission.priority as u64).saturating_sub(promotions),
+                    position,
+                    submission_id,
+                )
+            })
+            .collect();
+        submission_ids.sort_unstable();
+        submission_ids
+            .into_iter()
+            .map(|(class, _, submission_id)| (class, submission_id.clone()))
+            .collect()
+    }
+
+    pub fn handle_compilation_status(
+        &self,
+        invoker: &invoker::Invoker,
+        message: message::i2c::NotifyCompilationStatus,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(submission) = get_submission_on(&mut state, invoker, &message.submission_id)?
+        else {
+            return Ok(());
+        };
+        // The submission was finalized early, e.g. because of an error on another test
+        if submission.is_finalized() {
+            return Ok(());
+        }
+        let submission::CompilationState::InProgress { core, .. } =
+            *submission.get_compilation_state()
+        else {
+            bail!(
+                "Compilation status of submission {} was reported twice",
+                message.submission_id
+            );
+        };
+        if let Err(ref e) = message.result {
+            if e.is_retryable() {
+                return self.handle_infrastructure_failure(
+                    &mut state,
+                    invoker,
+                    &message.submission_id,
+                    e.clone(),
+                );
+            }
+        }
+        invoker.record_success();
+        submission.mark_compiled(message.result.clone())?;
+        write_to_journal(
+            &state.journal,
+            journal::Record::Compiled {
+                submission_id: message.submission_id.clone(),
+                result: message.result.clone(),
+            },
+        );
+        state
+            .busy_cores
+            .remove(&(invoker.get_name().to_string(), core));
+        if let Err(e) = message.result {
+            let verdict = match e {
+                errors::UserFailure(_) => verdict::SubmissionVerdict::CompilationError(e),
+                _ => verdict::SubmissionVerdict::Bug(e),
+            };
+            finalize_submission(&mut state, invoker, &message.submission_id, verdict)?;
+        }
+        Ok(())
+    }
+
+    pub fn handle_test_status(
+        &self,
+        invoker: &invoker::Invoker,
+        message: message::i2c::NotifyTestStatus,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(submission) = get_submission_on(&mut state, invoker, &message.submission_id)?
+        else {
+            return Ok(());
+        };
+        // Tests that were cancelled or abandoned may still be reported after the submission was
+        // finalized
+        if submission.is_finalized() {
+            return Ok(());
+        }
+
+        if !message.judgement_result.verdict.is_final() {
+            return submission.mark_in_progress(message.test, message.judgement_result);
+        }
+
+        invoker.record_success();
+        let core = match submission.get_test_state(message.test)? {
+            submission::TestState::Queued { core } => Some(*core),
+            _ => None,
+        };
+
+        let mut judgement_result = message.judgement_result;
+        if let Some(core) = core {
+            let policy = &self.config.tle_recheck;
+            if submission.get_recheck_count(message.test) < policy.runs
+                && submission.is_borderline_tle(&judgement_result, policy.margin_percent)
+            {
+                println!(
+                    "Rechecking borderline time limit on test {} of submission {}",
+                    message.test, message.submission_id
+                );
+                submission.mark_for_recheck(
+                    message.test,
+                    judgement_result,
+                    policy.different_invoker,
+                )?;
+                let waiting_to_move = submission.is_waiting_to_move();
+                state
+                    .busy_cores
+                    .remove(&(invoker.get_name().to_string(), core));
+                if waiting_to_move {
+                    move_submission(&mut state, invoker, &message.submission_id, false)?;
+                }
+                return Ok(());
+            }
+            judgement_result = submission.take_fastest_run(message.test, judgement_result);
+        }
+
+        let cancelled = submission.mark_judged(message.test, judgement_result.clone())?;
+        let verdict = if submission.is_finished() {
+            Some(submission.get_tests_verdict())
+        } else {
+            None
+        };
+        let waiting_to_move = submission.is_waiting_to_move();
+
+        write_to_journal(
+            &state.journal,
+            journal::Record::TestJudged {
+                submission_id: message.submission_id.clone(),
+                test: message.test,
+                judgement_result,
+            },
+        );
+
+        for core in core
+            .into_iter()
+            .chain(cancelled.iter().map(|&(_, core)| core))
+        {
+            state
+                .busy_cores
+                .remove(&(invoker.get_name().to_string(), core));
+        }
+
+        if !cancelled.is_empty() {
+            invoker.send_to_invoker(message::c2i::Message::CancelJudgementOnTests(
+                message::c2i::CancelJudgementOnTests {
+                    submission_id: message.submission_id.clone(),
+                    failed_tests: cancelled.into_iter().map(|(test, _)| test).collect(),
+                },
+            ))?;
+        }
+
+        if let Some(verdict) = verdict {
+            finalize_submission(&mut state, invoker, &message.submission_id, verdict)?;
+        } else if waiting_to_move {
+            // The rechecks that were held back are all sent to another invoker together
+            move_submission(&mut state, invoker, &message.submission_id, false)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_submission_error(
+        &self,
+        invoker: &invoker::Invoker,
+        message: message::i2c::NotifySubmissionError,
+    ) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let Some(submission) = get_submission_on(&mut state, invoker, &message.submission_id)?
+        else {
+            return Ok(());
+        };
+        if submission.is_finalized() {
+            return Ok(());
+        }
+        println!(
+            "Submission {} failed on invoker {}: {}",
+            message.submission_id,
+            invoker.get_name(),
+            message.error
+        );
+        if message.error.is_retryable() {
+            return self.handle_infrastructure_failure(
+                &mut state,
+                invoker,
+                &message.submission_id,
+                message.error,
+            );
+        }
+        finalize_submission(
+            &mut state,
+            invoker,
+            &message.submission_id,
+            verdict::SubmissionVerdict::Bug(message.error),
+        )
+    }
+
+    // Moves the submission to another invoker, unless it has been retried too many times already,
+    // and quarantines the invoker if it keeps failing
+    fn handle_infrastructure_failure(
+        &self,
+        state: &mut SchedulerState,
+        invoker: &invoker::Invoker,
+        submission_id: &str,
+        error: errors::Error,
+    ) -> Result<()> {
+        if invoker.record_failure(self.config.quarantine_after_failures) {
+            println!(
+                "Invoker {} is quarantined after {} consecutive failures, it gets no new \
+                 submissions until an administrator lifts the quarantine",
+                invoker.get_name(),
+                self.config.quarantine_after_failures
+            );
+        }
+
+        if get_submission(&mut state.submissions, submission_id)?.get_retry_count()
+            >= self.config.max_retries
+        {
+            return finalize_submission(
+                state,
+                invoker,
+                submission_id,
+                verdict::SubmissionVerdict::Bug(error),
+            );
+        }
+
+        println!(
+            "Retrying submission {submission_id} on another invoker after failure on invoker {}: \
+             {error}",
+            invoker.get_name()
+        );
+        move_submission(state, invoker, submission_id, true)
+    }
+
+    // Confirms the subscription with a Subscribed message and then streams the events of the
+    // submission. The confirmation has to be sent under the lock, so that no events are lost or
+    // reordered.
+    pub fn subscribe(
+        &self,
+        request_id: u64,
+        submission_id: &str,
+        subscriber: mpsc::UnboundedSender<message::c2f::Message>,
+        get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+    ) -> Result<()> {
+        self.with_submission(submission_id, get_problem, |submission| {
+            let _ = subscriber.send(message::c2f::Message::Subscribed(
+                message::c2f::Subscribed {
+                    request_id,
+                    result: Ok(()),
+                },
+            ));
+            submission.subscribe(subscriber);
+        })
+    }
+
+    // Drops the finalized submissions that were not needed for finalized_retention_ms from memory.
+    // Only checkpointed ones are dropped, so they can be loaded again.
+    pub fn evict_finalized(&self) {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        let retention = Duration::from_millis(self.config.finalized_retention_ms);
+        let checkpoints = &state.checkpoints;
+        state.submissions.retain(|submission_id, submission| {
+            !checkpoints.contains_key(submission_id)
+                || submission
+                    .get_finalized_at()
+                    .map_or(true, |finalized_at| finalized_at.elapsed() < retention)
+        });
+    }
+
+    // Waits until everything recorded so far is synced to disk
+    pub async fn flush_journal(&self) -> Result<()> {
+        let durable = self.state.lock().unwrap().journal.flush();
+        durable.wait().await
+    }
+
+    // Fails the compilations that took longer than compilation_timeout_ms, e.g. because the invoker
+    // lost track of them
+    pub fn expire_compilations(&self, invokers: &[Arc<invoker::Invoker>]) {
+        let mut state = self.state.lock().unwrap();
+        let timeout = Duration::from_millis(self.config.compilation_timeout_ms);
+
+        let mut expired = Vec::new();
+        for submission_id in &state.queue {
+            if let submission::CompilationState::InProgress {
+                invoker: ref invoker_name,
+                started_at,
+                ..
+            } = *state.submissions[submission_id].get_compilation_state()
+            {
+                if started_at.elapsed() > timeout {
+                    expired.push((submission_id.clone(), invoker_name.clone()));
+                }
+            }
+        }
+
+        for (submission_id, invoker_name) in expired {
+            let Some(invoker) = invokers
+                .iter()
+                .find(|invoker| invoker.get_name() == invoker_name)
+            else {
+                continue;
+            };
+            println!(
+                "Compilation of submission {submission_id} on invoker {invoker_name} timed out"
+            );
+            let result = self.handle_infrastructure_failure(
+                &mut state,
+                invoker,
+                &submission_id,
+                errors::InvokerFailure("Compilation did not finish in time".to_string()),
+            );
+            if let Err(e) = result {
+                println!("Failed to handle timeout of submission {submission_id}: {e:?}");
+            }
+        }
+    }
+
+    // Stops sending new submissions to invokers. Those that are already on an invoker are judged
+    // to the end.
+    pub fn stop(&self) {
+        self.state.lock().unwrap().stopped = true;
+    }
+
+    pub fn has_submissions_on_invokers(&self) -> bool {
+        let state = self.state.lock().unwrap();
+        state
+            .queue
+            .iter()
+            .any(|submission_id| state.submissions[submission_id].get_invoker().is_some())
+    }
+
+    pub fn get_busy_core_count(&self, invoker_name: &str) -> usize {
+        self.state
+            .lock()
+            .unwrap()
+            .busy_cores
+            .keys()
+            .filter(|(name, _)| name == invoker_name)
+            .count()
+    }
+
+    pub fn get_committed_ram(&self, invoker_name: &str) -> u64 {
+        get_committed_ram(&self.state.lock().unwrap(), invoker_name)
+    }
+
+    // Puts the compilations and tests that were assigned to the invoker back to the queue, so that
+    // they are picked up by other invokers
+    pub fn handle_invoker_lost(&self, invoker_name: &str) {
+        let mut state = self.state.lock().unwrap();
+        state.busy_cores.retain(|(name, _), _| name != invoker_name);
+        for submission in state.submissions.values_mut() {
+            if !submission.is_finalized() && submission.get_invoker() == Some(invoker_name) {
+                println!(
+                    "Requeueing submission {} because invoker {invoker_name} is lost",
+                    submission.id
+                );
+                submission.reset_to_pending();
+            }
+        }
+    }
+}
+
+// Picks the submission that gets the next free core of the invoker: the highest priority class with
+// work for the invoker comes first, then the owner that is the furthest behind its fair share, then
+// the submission that arrived first
+fn pick_submission(
+    state: &SchedulerState,
+    config: &config::SchedulerConfig,
+    submission_ids: &[(u64, String)],
+    invokers: &[Arc<invoker::Invoker>],
+    invoker_states: &[invoker::InvokerState],
+    invoker: &invoker::Invoker,
+    invoker_state: &invoker::InvokerState,
+    available_ram: u64,
+    can_compile: bool,
+) -> Option<String> {
+    let mut best: Option<(u64, f64, &String)> = None;
+    for (class, submission_id) in submission_ids {
+        if let Some((best_class, ..)) = best {
+            if *class > best_class {
+                break;
+            }
+        }
+        let submission = &state.submissions[submission_id];
+        let has_work = match submission.get_compilation_state() {
+            // An invoker the submission has left is only used if every invoker that could take
+            // the submission instead is just as bad
+            submission::CompilationState::Pending => {
+                let reluctance = submission.get_reluctance(invoker.get_name());
+                can_compile
+                    && !state.stopped
+                    && can_take(config, invoker, invoker_state, submission)
+                    && (reluctance == 0
+                        || invokers
+                            .iter()
+                            .zip(invoker_states)
+                            .filter(|(other, other_state)| {
+                                can_take(config, other, other_state, submission)
+                            })
+                            .all(|(other, _)| {
+                                submission.get_reluctance(other.get_name()) >= reluctance
+                            }))
+            }
+            submission::CompilationState::Done {
+                invoker: ref invoker_name,
+            } => {
+                invoker_name == invoker.get_name()
+                    && submission.next_runnable_test().is_some()
+                    && submission.get_test_ram() <= available_ram
+            }
+            submission::CompilationState::InProgress { .. } => false,
+        };
+        if !has_work {
+            continue;
+        }
+        let pass = state.fair_share.get_pass(&submission.owner);
+        if best.map_or(true, |(_, best_pass, _)| pass < best_pass) {
+            best = Some((*class, pass, submission_id));
+        }
+    }
+    best.map(|(_, _, submission_id)| submission_id.clone())
+}
+
+// Whether a new submission may be sent to the invoker. A draining invoker finishes the submissions it
+// already has, but gets no new ones. Neither does an invoker that could never fit a single test of
+// the submission.
+fn can_take(
+    config: &config::SchedulerConfig,
+    invoker: &invoker::Invoker,
+    invoker_state: &invoker::InvokerState,
+    submission: &submission::Submission,
+) -> bool {
+    !invoker_state.draining
+        && !invoker_state.quarantined
+        && is_invoker_suitable(config, invoker, submission)
+        && submission.get_test_ram() <= invoker_state.designated_ram
+}
+
+// Whether the invoker has the toolchain and the labels required to judge the submission
+fn is_invoker_suitable(
+    config: &config::SchedulerConfig,
+    invoker: &invoker::Invoker,
+    submission: &submission::Submission,
+) -> bool {
+    let has_labels = |required: &HashMap<String, Vec<String>>, key: &str| {
+        required
+            .get(key)
+            .map_or(true, |labels| invoker.has_labels(labels))
+    };
+    invoker.supports_language(&submission.language)
+        && has_labels(&config.problem_labels, &submission.problem_id)
+        && has_labels(&config.language_labels, &submission.language)
+}
+
+fn get_committed_ram(state: &SchedulerState, invoker_name: &str) -> u64 {
+    state
+        .busy_cores
+        .iter()
+        .filter(|((name, _), _)| name == invoker_name)
+        .map(|(_, ram)| ram)
+        .sum()
+}
+
+fn make_added_record(submission: &submission::Submission) -> journal::Record {
+    journal::Record::Added {
+        submission_id: submission.id.clone(),
+        problem_id: submission.problem_id.clone(),
+        revision_id: submission.revision_id.clone(),
+        language: submission.language.clone(),
+        files: submission.files.clone(),
+        rejudge_of: submission.rejudge_of.clone(),
+        priority: submission.priority,
+        owner: submission.owner.clone(),
+    }
+}
+
+fn get_submission<'a>(
+    submissions: &'a mut HashMap<String, submission::Submission>,
+    submission_id: &str,
+) -> Result<&'a mut submission::Submission> {
+    submissions
+        .get_mut(submission_id)
+        .with_context(|| format!("Unknown submission {submission_id}"))
+}
+
+// Returns None if the submission was moved away from the invoker, in which case messages the
+// invoker sent before it learned about that are ignored
+fn get_submission_on<'a>(
+    state: &'a mut SchedulerState,
+    invoker: &invoker::Invoker,
+    submission_id: &str,
+) -> Result<Option<&'a mut submission::Submission>> {
+    let submission = get_submission(&mut state.submissions, submission_id)?;
+    if submission.get_invoker() != Some(invoker.get_name()) {
+        if submission.has_left(invoker.get_name()) {
+            return Ok(None);
+        }
+        bail!(
+            "Submission {submission_id} is not judged on invoker {}",
+            invoker.get_name()
+        );
+    }
+    Ok(Some(submission))
+}
+
+// Takes the submission away from the invoker, so that it is compiled again and its remaining tests
+// are judged elsewhere
+fn move_submission(
+    state: &mut SchedulerState,
+    invoker: &invoker::Invoker,
+    submission_id: &str,
+    failed: bool,
+) -> Result<()> {
+    let submission = state
+        .submissions
+        .get_mut(submission_id)
+        .with_context(|| format!("Unknown submission {submission_id}"))?;
+    for core in submission.get_busy_cores() {
+        state
+            .busy_cores
+            .remove(&(invoker.get_name().to_string(), core));
+    }
+    submission.leave_invoker(invoker.get_name(), failed);
+    // Let the invoker drop whatever is left of the submission
+    invoker.send_to_invoker(message::c2i::Message::FinalizeSubmission(
+        message::c2i::FinalizeSubmission {
+            submission_id: submission_id.to_string(),
+        },
+    ))
+}
+
+fn finalize_submission(
+    state: &mut SchedulerState,
+    invoker: &invoker::Invoker,
+    submission_id: &str,
+    verdict: verdict::SubmissionVerdict,
+) -> Result<()> {
+    // Finalized submissions are kept around, so that their results can be queried
+    if let Some(submission) = state.submissions.get_mut(submission_id) {
+        for core in submission.get_busy_cores() {
+            state
+                .busy_cores
+                .remove(&(invoker.get_name().to_string(), core));
+        }
+        record_verdict(state, submission_id, verdict);
+    }
+    state.queue.retain(|id| id != submission_id);
+    invoker.send_to_invoker(message::c2i::Message::FinalizeSubmission(
+        message::c2i::FinalizeSubmission {
+            submission_id: submission_id.to_string(),
+        },
+    ))
+}
+
+// Failing to record an event that has already happened cannot be reported to anyone, so the writer
+// only logs the error. After a restart, the event is lost and the corresponding work is redone.
+fn write_to_journal(journal: &journal::Journal, record: journal::Record) {
+    let _ = journal.append(&record);
+}
+
+// Finalizes the submission and moves its history to a checkpoint, so that it can be evicted from
+// memory later
+fn record_verdict(
+    state: &mut SchedulerState,
+    submission_id: &str,
+    verdict: verdict::SubmissionVerdict,
+) {
+    let Some(submission) = state.submissions.get_mut(submission_id) else {
+        return;
+    };
+    submission.finalize(verdict.clone());
+    write_to_journal(
+        &state.journal,
+        journal::Record::Finalized {
+            submission_id: submission_id.to_string(),
+            verdict,
+        },
+    );
+    match state.journal.checkpoint(&make_history(submission)) {
+        Ok(checkpoint) => {
+            state
+                .checkpoints
+                .insert(submission_id.to_string(), checkpoint);
+        }
+        Err(e) => println!("Failed to checkpoint submission {submission_id}: {e:?}"),
+    }
+}
+
+// The records that restore the submission as it is now
+fn make_history(submission: &submission::Submission) -> Vec<journal::Record> {
+    let mut records = vec![make_added_record(submission)];
+    if let Some(result) = submission.get_compilation_result() {
+        records.push(journal::Record::Compiled {
+            submission_id: submission.id.clone(),
+            result: result.clone(),
+        });
+    }
+    for (test, judgement_result) in submission.get_judged_tests() {
+        records.push(journal::Record::TestJudged {
+            submission_id: submission.id.clone(),
+            test,
+            judgement_result,
+        });
+    }
+    if let Some(verdict) = submission.get_verdict() {
+        records.push(journal::Record::Finalized {
+            submission_id: submission.id.clone(),
+            verdict: verdict.clone(),
+        });
+    }
+    records
+}
+
+fn apply_record(
+    submissions: &mut HashMap<String, submission::Submission>,
+    record: journal::Record,
+    get_problem: impl Fn(&str, &str) -> Result<Arc<problem::config::ProblemRevision>>,
+) -> Result<()> {
+    match record {
+        journal::Record::Added {
+            submission_id,
+            problem_id,
+            revision_id,
+            language,
+            files,
+            rejudge_of,
+            priority,
+            owner,
+        } => {
+            if submissions.contains_key(&submission_id) {
+                bail!("Submission {submission_id} is added twice");
+            }
+            let problem = get_problem(&problem_id, &revision_id)?;
+            let mut submission = submission::Submission::new(
+                submission_id.clone(),
+                problem_id,
+                revision_id,
+                problem,
+                language,
+                files,
+            );
+            submission.rejudge_of = rejudge_of;
+            submission.priority = priority;
+            submission.owner = owner;
+            submissions.insert(submission_id, submission);
+        }
+        journal::Record::Compiled {
+            submission_id,
+            result,
+        } => {
+            get_submission(submissions, &submission_id)?.restore_compilation_status(result);
+        }
+        journal::Record::TestJudged {
+            submission_id,
+            test,
+            judgement_result,
+        } => {
+            get_submission(submissions, &submission_id)?
+                .restore_judgement(test, judgement_result)?;
+        }
+        journal::Record::Finalized {
+            submission_id,
+            verdict,
+        } => {
+            get_submission(submissions, &submission_id)?.finalize(verdict);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,611 @@
+use crate::{
+    errors,
+    message::{c2f, c2i},
+    problem::config,
+    verdict,
+};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+pub struct Submission {
+    pub id: String,
+    pub problem_id: String,
+    pub revision_id: String,
+    pub problem: Arc<config::ProblemRevision>,
+    condensation: config::Condensation,
+    pub language: String,
+    pub files: HashMap<String, Vec<u8>>,
+    // The submission this one is a rejudge of
+    pub rejudge_of: Option<String>,
+    pub priority: Priority,
+    pub owner: Owner,
+    // When the submission was queued, or when the conductor was restarted
+    pub queued_at: Instant,
+    // Invokers that failed to judge the submission because of infrastructure faults, and how many
+    // times that happened in total
+    failed_invokers: HashSet<String>,
+    retries: u64,
+    // Invokers the submission was moved away from to recheck a test elsewhere. Both these and failed
+    // invokers are used again if there is no other choice.
+    avoided_invokers: HashSet<String>,
+    // Earlier runs of tests that are being rechecked because of a borderline TLE
+    recheck_attempts: HashMap<u64, Vec<verdict::TestJudgementResult>>,
+    // Tests to be rechecked on another invoker. They are held back until nothing else is left to do
+    // on the current invoker, so that all of them are moved at once.
+    recheck_elsewhere: HashSet<u64>,
+    compilation: CompilationState,
+    tests: Vec<TestState>,
+    verdict: Option<verdict::SubmissionVerdict>,
+    finalized_at: Option<Instant>,
+    // Everything that happened to the submission so far, replayed to new subscribers
+    events: Vec<c2f::Event>,
+    subscribers: Vec<mpsc::UnboundedSender<c2f::Message>>,
+}
+
+// Higher classes come first
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    LiveContest,
+    Practice,
+    Rejudge,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Practice
+    }
+}
+
+// Cores are shared fairly between owners, see the scheduler
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Owner {
+    pub contest_id: Option<String>,
+    pub user_id: Option<String>,
+}
+
+pub enum CompilationState {
+    Pending,
+    InProgress {
+        invoker: String,
+        core: u64,
+        started_at: Instant,
+    },
+    Done {
+        invoker: String,
+    },
+}
+
+pub enum TestState {
+    Pending,
+    Queued { core: u64 },
+    Judged(verdict::TestJudgementResult),
+    Ignored,
+}
+
+impl Submission {
+    pub fn new(
+        id: String,
+        problem_id: String,
+        revision_id: String,
+        problem: Arc<config::ProblemRevision>,
+        language: String,
+        files: HashMap<String, Vec<u8>>,
+    ) -> Self {
+        let test_count = problem.dependency_graph.dependents_of.len();
+        Self {
+            id,
+            problem_id,
+            revision_id,
+            condensation: problem.dependency_graph.condense(),
+            problem,
+            language,
+            files,
+            rejudge_of: None,
+            priority: Priority::default(),
+            owner: Owner::default(),
+            queued_at: Instant::now(),
+            failed_invokers: HashSet::new(),
+            retries: 0,
+            avoided_invokers: HashSet::new(),
+            recheck_attempts: HashMap::new(),
+            recheck_elsewhere: HashSet::new(),
+            compilation: CompilationState::Pending,
+            tests: (0..test_count).map(|_| TestState::Pending).collect(),
+            verdict: None,
+            finalized_at: None,
+            events: Vec::new(),
+            subscribers: Vec::new(),
+        }
+    }
+
+    // Sends the history of the submission to the subscriber, and then keeps it updated until the
+    // submission is finalized
+    pub fn subscribe(&mut self, subscriber: mpsc::UnboundedSender<c2f::Message>) {
+        for event in &self.events {
+            if subscriber
+                .send(self.make_event_message(event.clone()))
+                .is_err()
+            {
+                return;
+            }
+        }
+        if self.verdict.is_none() {
+            self.subscribers.push(subscriber);
+        }
+    }
+
+    fn make_event_message(&self, event: c2f::Event) -> c2f::Message {
+        c2f::Message::SubmissionEvent(c2f::SubmissionEvent {
+            submission_id: self.id.clone(),
+            event,
+        })
+    }
+
+    fn emit(&mut self, event: c2f::Event) {
+        // Subscribers whose connection is closed are dropped
+        let mut subscribers = std::mem::take(&mut self.subscribers);
+        subscribers.retain(|subscriber| {
+            subscriber
+                .send(self.make_event_message(event.clone()))
+                .is_ok()
+        });
+        self.subscribers = subscribers;
+        self.events.push(event);
+    }
+
+    fn emit_test_status(&mut self, test: u64, judgement_result: verdict::TestJudgementResult) {
+        self.emit(c2f::Event::TestStatus(c2f::TestStatus {
+            test,
+            judgement_result,
+        }));
+    }
+
+    pub fn get_compilation_state(&self) -> &CompilationState {
+        &self.compilation
+    }
+
+    // The invoker the submission was sent to, if any
+    pub fn get_invoker(&self) -> Option<&str> {
+        match self.compilation {
+            CompilationState::Pending => None,
+            CompilationState::InProgress { ref invoker, .. }
+            | CompilationState::Done { ref invoker } => Some(invoker),
+        }
+    }
+
+    pub fn make_add_submission(&self, compilation_core: u64) -> c2i::AddSubmission {
+        c2i::AddSubmission {
+            compilation_core,
+            submission_id: self.id.clone(),
+            problem_id: self.problem_id.clone(),
+            revision_id: self.revision_id.clone(),
+            files: self.files.clone(),
+            language: self.language.clone(),
+            invocation_limits: self.problem.invocation_limits.clone(),
+        }
+    }
+
+    // Whether the test exceeded the time limit by at most margin_percent in every block where it
+    // exceeded it at all
+    pub fn is_borderline_tle(
+        &self,
+        judgement_result: &verdict::TestJudgementResult,
+        margin_percent: u64,
+    ) -> bool {
+        if !matches!(
+            judgement_result.verdict,
+            verdict::TestVerdict::TimeLimitExceeded
+        ) {
+            return false;
+        }
+        let mut exceeded = false;
+        for (block, stat) in &judgement_result.invocation_stats {
+            let Some(limit) = self.problem.invocation_limits.get(block) else {
+                continue;
+            };
+            if stat.cpu_time > limit.cpu_time {
+                if stat.cpu_time.as_nanos() * 100
+                    > limit.cpu_time.as_nanos() * (100 + margin_percent as u128)
+                {
+                    return false;
+                }
+                exceeded = true;
+            }
+        }
+        // A TLE on real time alone is not a matter of CPU timing noise
+        exceeded
+    }
+
+    pub fn get_recheck_count(&self, test: u64) -> u64 {
+        self.recheck_attempts
+            .get(&test)
+            .map_or(0, |attempts| attempts.len() as u64)
+    }
+
+    // Puts a queued test back to the queue, remembering the result of the run. If the test is to be
+    // rechecked elsewhere, it is not run again until the submission leaves the invoker.
+    pub fn mark_for_recheck(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+        elsewhere: bool,
+    ) -> Result<()> {
+        let TestState::Queued { .. } = self.get_test_state(test)? else {
+            bail!(
+                "Test {test} of submission {} was judged, but it was not queued",
+                self.id
+            );
+        };
+        self.tests[test as usize] = TestState::Pending;
+        self.recheck_attempts
+            .entry(test)
+            .or_default()
+            .push(judgement_result);
+        if elsewhere {
+            self.recheck_elsewhere.insert(test);
+        }
+        Ok(())
+    }
+
+    // Whether the only work left on the current invoker is rechecks that have to happen elsewhere
+    pub fn is_waiting_to_move(&self) -> bool {
+        self.recheck_elsewhere
+            .iter()
+            .any(|&test| matches!(self.tests[test as usize], TestState::Pending))
+            && self.next_runnable_test().is_none()
+            && !self
+                .tests
+                .iter()
+                .any(|state| matches!(state, TestState::Queued { .. }))
+    }
+
+    // Of all runs of the test, returns the one with the least CPU time
+    pub fn take_fastest_run(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    ) -> verdict::TestJudgementResult {
+        let mut runs = self.recheck_attempts.remove(&test).unwrap_or_default();
+        runs.push(judgement_result);
+        runs.into_iter()
+            .min_by_key(|run| {
+                run.invocation_stats
+                    .values()
+                    .map(|stat| stat.cpu_time)
+                    .sum::<std::time::Duration>()
+            })
+            .unwrap()
+    }
+
+    // RAM to reserve on the invoker while a test runs. User programs of different blocks may run
+    // simultaneously, so their memory limits add up.
+    pub fn get_test_ram(&self) -> u64 {
+        self.problem
+            .invocation_limits
+            .values()
+            .map(|limit| limit.memory as u64)
+            .sum()
+    }
+
+    pub fn mark_compiling(&mut self, invoker: String, core: u64) {
+        self.compilation = CompilationState::InProgress {
+            invoker,
+            core,
+            started_at: Instant::now(),
+        };
+    }
+
+    pub fn mark_compiled(&mut self, result: Result<String, errors::Error>) -> Result<()> {
+        let CompilationState::InProgress { ref invoker, .. } = self.compilation else {
+            bail!(
+                "Submission {} was compiled, but it was not being compiled",
+                self.id
+            );
+        };
+        self.compilation = CompilationState::Done {
+            invoker: invoker.clone(),
+        };
+        self.emit(c2f::Event::CompilationStatus(result));
+        Ok(())
+    }
+
+    // Restores the compilation status reported before a restart. Compiled programs are only kept by
+    // the invoker, so the submission stays pending and is compiled again.
+    pub fn restore_compilation_status(&mut self, result: Result<String, errors::Error>) {
+        self.emit(c2f::Event::CompilationStatus(result));
+    }
+
+    // Forgets about the invoker the submission was sent to. The submission has to be compiled again,
+    // and the tests that were queued but not judged are run again. Judged tests are kept.
+    pub fn reset_to_pending(&mut self) {
+        self.compilation = CompilationState::Pending;
+        self.recheck_elsewhere.clear();
+        for state in &mut self.tests {
+            if let TestState::Queued { .. } = state {
+                *state = TestState::Pending;
+            }
+        }
+    }
+
+    // Requeues the submission so that it is judged on another invoker. After an infrastructure
+    // fault, the submission only goes back to that invoker if no other one can take it.
+    pub fn leave_invoker(&mut self, invoker_name: &str, failed: bool) {
+        if failed {
+            self.failed_invokers.insert(invoker_name.to_string());
+            self.retries += 1;
+        } else {
+            self.avoided_invokers.insert(invoker_name.to_string());
+        }
+        self.reset_to_pending();
+    }
+
+    // Whether the invoker used to judge the submission, so that late messages from it are expected
+    pub fn has_left(&self, invoker_name: &str) -> bool {
+        self.failed_invokers.contains(invoker_name) || self.avoided_invokers.contains(invoker_name)
+    }
+
+    // How reluctant the scheduler is to send the submission to the invoker: fresh invokers come
+    // first, then the ones it was moved away from, and the ones that failed on it come last
+    pub fn get_reluctance(&self, invoker_name: &str) -> u64 {
+        if self.failed_invokers.contains(invoker_name) {
+            2
+        } else if self.avoided_invokers.contains(invoker_name) {
+            1
+        } else {
+            0
+        }
+    }
+
+    pub fn get_retry_count(&self) -> u64 {
+        self.retries
+    }
+
+    pub fn get_test_state(&self, test: u64) -> Result<&TestState> {
+        self.tests
+            .get(test as usize)
+            .with_context(|| format!("Submission {} has no test {test}", self.id))
+    }
+
+    // A test is runnable once every test it depends on outside its own component has passed. The
+    // tests of a component, e.g. a complete group, depend on each other, so they run together.
+    pub fn next_runnable_test(&self) -> Option<u64> {
+        if !matches!(self.compilation, CompilationState::Done { .. }) {
+            return None;
+        }
+        self.tests
+            .iter()
+            .enumerate()
+            .position(|(test, state)| {
+                matches!(state, TestState::Pending)
+                    && !self.recheck_elsewhere.contains(&(test as u64))
+                    && self.have_dependencies_passed(test as u64)
+            })
+            .map(|test| test as u64)
+    }
+
+    fn have_dependencies_passed(&self, test: u64) -> bool {
+        let component = self.condensation.component_of[test as usize];
+        self.condensation.dependencies_of[component]
+            .iter()
+            .flat_map(|&dependency| &self.condensation.components[dependency])
+            .all(|&dependency| {
+                matches!(
+                    self.tests[dependency as usize],
+                    TestState::Judged(ref judgement_result)
+                        if judgement_result.verdict.is_successful()
+                )
+            })
+    }
+
+    pub fn mark_queued(&mut self, test: u64, core: u64) {
+        self.tests[test as usize] = TestState::Queued { core };
+        self.emit_test_status(test, make_placeholder_result(verdict::TestVerdict::InQueue));
+    }
+
+    // Records an intermediate status of a test, e.g. Running
+    pub fn mark_in_progress(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    ) -> Result<()> {
+        match self.get_test_state(test)? {
+            TestState::Queued { .. } => {
+                self.emit_test_status(test, judgement_result);
+                Ok(())
+            }
+            TestState::Ignored => Ok(()),
+            TestState::Pending | TestState::Judged(_) => {
+                bail!(
+                    "Status of test {test} of submission {} was reported, but the test is not \
+                     queued",
+                    self.id
+                );
+            }
+        }
+    }
+
+    // Records the final result of a test. Returns the cores of the previously queued tests that were
+    // ignored because of a failure, along with the test ids.
+    pub fn mark_judged(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    ) -> Result<Vec<(u64, u64)>> {
+        match self.get_test_state(test)? {
+            TestState::Queued { .. } => {}
+            TestState::Ignored => {
+                // The test was cancelled, but the invoker judged it before it got the message
+                return Ok(Vec::new());
+            }
+            TestState::Pending | TestState::Judged(_) => {
+                bail!(
+                    "Test {test} of submission {} was judged, but it was not queued",
+                    self.id
+                );
+            }
+        }
+
+        Ok(self.record_judgement(test, judgement_result))
+    }
+
+    // Records the result of a test that is not going to be run, e.g. one judged before a restart or
+    // one carried over from the original submission by a rejudge
+    pub fn restore_judgement(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    ) -> Result<()> {
+        match self.get_test_state(test)? {
+            TestState::Pending => {
+                self.record_judgement(test, judgement_result);
+            }
+            // Ignored because a test it depends on has failed
+            TestState::Ignored => {}
+            TestState::Queued { .. } | TestState::Judged(_) => {
+                bail!("Test {test} of submission {} is judged twice", self.id);
+            }
+        }
+        Ok(())
+    }
+
+    fn record_judgement(
+        &mut self,
+        test: u64,
+        judgement_result: verdict::TestJudgementResult,
+    ) -> Vec<(u64, u64)> {
+        let failed = !judgement_result.verdict.is_successful();
+        self.emit_test_status(test, judgement_result.clone());
+        self.tests[test as usize] = TestState::Judged(judgement_result);
+
+        let mut cancelled = Vec::new();
+        if failed {
+            let problem = self.problem.clone();
+            let mut stack = vec![test];
+            while let Some(test) = stack.pop() {
+                let Some(dependents) = problem.dependency_graph.dependents_of.get(&test) else {
+                    continue;
+                };
+                for &dependent in dependents {
+                    match self.tests[dependent as usize] {
+                        TestState::Pending => {}
+                        TestState::Queued { core } => cancelled.push((dependent, core)),
+                        TestState::Judged(_) | TestState::Ignored => continue,
+                    }
+                    self.tests[dependent as usize] = TestState::Ignored;
+                    self.emit_test_status(
+                        dependent,
+                        make_placeholder_result(verdict::TestVerdict::Ignored),
+                    );
+                    stack.push(dependent);
+                }
+            }
+        }
+
+        cancelled
+    }
+
+    // Cores occupied by this submission, including the compilation core
+    pub fn get_busy_cores(&self) -> Vec<u64> {
+        let compilation_core = match self.compilation {
+            CompilationState::InProgress { core, .. } => Some(core),
+            _ => None,
+        };
+        compilation_core
+            .into_iter()
+            .chain(self.tests.iter().filter_map(|state| match state {
+                TestState::Queued { core } => Some(*core),
+                _ => None,
+            }))
+            .collect()
+    }
+
+    pub fn get_test_count(&self) -> u64 {
+        self.tests.len() as u64
+    }
+
+    pub fn get_judged_tests(&self) -> Vec<(u64, verdict::TestJudgementResult)> {
+        self.tests
+            .iter()
+            .enumerate()
+            .filter_map(|(test, state)| match state {
+                TestState::Judged(judgement_result) => {
+                    Some((test as u64, judgement_result.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.tests
+            .iter()
+            .all(|state| matches!(state, TestState::Judged(_) | TestState::Ignored))
+    }
+
+    // The verdict of a submission whose tests have all been judged
+    pub fn get_tests_verdict(&self) -> verdict::SubmissionVerdict {
+        for (test, state) in self.tests.iter().enumerate() {
+            if let TestState::Judged(ref judgement_result) = state {
+                if !judgement_result.verdict.is_successful() {
+                    return verdict::SubmissionVerdict::FailedOnTest {
+                        test: test as u64,
+                        verdict: judgement_result.verdict.clone(),
+                    };
+                }
+            }
+        }
+        verdict::SubmissionVerdict::Accepted
+    }
+
+    // The submission may be finalized early, e.g. because of an error, so whatever was still being
+    // compiled or judged is abandoned. The cores it occupied have to be freed by the caller before.
+    pub fn finalize(&mut self, verdict: verdict::SubmissionVerdict) {
+        if let CompilationState::InProgress { ref invoker, .. } = self.compilation {
+            self.compilation = CompilationState::Done {
+                invoker: invoker.clone(),
+            };
+        }
+        for state in &mut self.tests {
+            if let TestState::Queued { .. } = state {
+                *state = TestState::Ignored;
+            }
+        }
+        self.verdict = Some(verdict.clone());
+        self.finalized_at = Some(Instant::now());
+        self.emit(c2f::Event::Finalized(verdict));
+        // No more events are going to happen
+        self.subscribers.clear();
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.verdict.is_some()
+    }
+
+    pub fn get_verdict(&self) -> Option<&verdict::SubmissionVerdict> {
+        self.verdict.as_ref()
+    }
+
+    pub fn get_finalized_at(&self) -> Option<Instant> {
+        self.finalized_at
+    }
+
+    // The last reported compilation status, if any
+    pub fn get_compilation_result(&self) -> Option<&Result<String, errors::Error>> {
+        self.events.iter().rev().find_map(|event| match event {
+            c2f::Event::CompilationStatus(result) => Some(result),
+            _ => None,
+        })
+    }
+}
+
+fn make_placeholder_result(verdict: verdict::TestVerdict) -> verdict::TestJudgementResult {
+    verdict::TestJudgementResult {
+        verdict,
+        logs: HashMap::new(),
+        invocation_stats: HashMap::new(),
+    }
+}
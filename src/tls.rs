@@ -0,0 +1,77 @@
+use crate::config;
+use anyhow::{anyhow, bail, Context, Result};
+use std::io::BufReader;
+use std::sync::Arc;
+use tokio_rustls::rustls;
+
+fn read_certificates(path: &str) -> Result<Vec<rustls::Certificate>> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse certificates from {path}"))?;
+    if certificates.is_empty() {
+        bail!("{path} contains no certificates");
+    }
+    Ok(certificates.into_iter().map(rustls::Certificate).collect())
+}
+
+fn read_private_key(path: &str) -> Result<rustls::PrivateKey> {
+    let file = std::fs::File::open(path).with_context(|| format!("Failed to open {path}"))?;
+    for item in rustls_pemfile::read_all(&mut BufReader::new(file))
+        .with_context(|| format!("Failed to parse {path}"))?
+    {
+        match item {
+            rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(rustls::PrivateKey(key)),
+            _ => {}
+        }
+    }
+    bail!("{path} contains no private key")
+}
+
+pub fn make_acceptor(tls_config: &config::TlsConfig) -> Result<tokio_rustls::TlsAcceptor> {
+    let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+    let builder = match tls_config.client_ca {
+        Some(ref client_ca) => {
+            let mut roots = rustls::RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots
+                    .add(&certificate)
+                    .with_context(|| format!("Invalid CA certificate in {client_ca}"))?;
+            }
+            builder
+                .with_client_cert_verifier(rustls::server::AllowAnyAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let server_config = builder
+        .with_single_cert(
+            read_certificates(&tls_config.certificate)?,
+            read_private_key(&tls_config.key)?,
+        )
+        .context("Invalid certificate or key")?;
+
+    Ok(tokio_rustls::TlsAcceptor::from(Arc::new(server_config)))
+}
+
+// A client certificate has already been verified against the CA during the TLS handshake. This
+// additionally checks that it was issued for the invoker, i.e. that the name the invoker claims in
+// the handshake is one of the DNS names of the certificate.
+pub fn verify_invoker_certificate(
+    certificate: &rustls::Certificate,
+    invoker_name: &str,
+) -> Result<()> {
+    let certificate = webpki::EndEntityCert::try_from(certificate.0.as_ref())
+        .map_err(|e| anyhow!("Failed to parse client certificate: {e}"))?;
+    let name = webpki::DnsNameRef::try_from_ascii_str(invoker_name).map_err(|_| {
+        anyhow!(
+            "Invoker name {invoker_name:?} is not a valid DNS name, so it cannot be checked \
+             against the client certificate"
+        )
+    })?;
+    certificate
+        .verify_is_valid_for_dns_name(name)
+        .map_err(|e| anyhow!("Client certificate was not issued for invoker {invoker_name:?}: {e}"))
+}
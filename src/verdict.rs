@@ -1,7 +1,8 @@
+use crate::errors;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum TestVerdict {
     InQueue,
     Running,
@@ -21,14 +22,14 @@ pub enum TestVerdict {
     CheckerFailed,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct TestJudgementResult {
     pub verdict: TestVerdict,
     pub logs: HashMap<String, Vec<u8>>,
     pub invocation_stats: HashMap<String, InvocationStat>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InvocationStat {
     pub real_time: std::time::Duration,
     pub cpu_time: std::time::Duration,
@@ -37,15 +38,36 @@ pub struct InvocationStat {
     pub memory: usize,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct InvocationLimit {
     pub real_time: std::time::Duration,
     pub cpu_time: std::time::Duration,
     pub memory: usize,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub enum ExitStatus {
     ExitCode(u8),
     Signal(u8),
 }
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub enum SubmissionVerdict {
+    Accepted,
+    FailedOnTest { test: u64, verdict: TestVerdict },
+    CompilationError(errors::Error),
+    Bug(errors::Error),
+}
+
+impl TestVerdict {
+    pub fn is_final(&self) -> bool {
+        !matches!(self, TestVerdict::InQueue | TestVerdict::Running)
+    }
+
+    pub fn is_successful(&self) -> bool {
+        matches!(
+            self,
+            TestVerdict::Accepted | TestVerdict::PartialSolution(_) | TestVerdict::Ignored
+        )
+    }
+}